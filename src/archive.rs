@@ -0,0 +1,244 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use flate2::read::DeflateDecoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Result, Seek, SeekFrom, Take};
+use std::path::{Path, PathBuf};
+use zip::{CompressionMethod, ZipArchive};
+
+/// Where an entry is in the archive
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    start: u64,
+    compressed_size: u64,
+    size: u64,
+    deflated: bool,
+}
+
+/// Entries of a zip archive, the central directory is read only once
+#[derive(Debug)]
+pub struct ZipIndex {
+    path: PathBuf,
+    entries: HashMap<String, Location>,
+}
+
+impl ZipIndex {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut zip = ZipArchive::new(BufReader::new(File::open(path)?))?;
+        let mut entries = HashMap::with_capacity(zip.len());
+
+        for i in 0..zip.len() {
+            let entry = zip.by_index_raw(i)?;
+            let deflated = match entry.compression() {
+                CompressionMethod::Stored => false,
+                CompressionMethod::Deflated => true,
+                _ => continue, // Not readable
+            };
+            entries.insert(
+                entry.name().to_string(),
+                Location {
+                    start: entry.data_start(),
+                    compressed_size: entry.compressed_size(),
+                    size: entry.size(),
+                    deflated,
+                },
+            );
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    pub fn entry(&self, name: &str) -> Result<ZipEntry> {
+        let location =
+            self.entries.get(name).copied().ok_or_else(|| {
+                Error::new(ErrorKind::NotFound, format!("{name} not in the archive"))
+            })?;
+        let file = File::open(&self.path)?;
+
+        Ok(match location.deflated {
+            false => ZipEntry::Stored {
+                file,
+                start: location.start,
+                size: location.size,
+                position: 0,
+            },
+            true => ZipEntry::Deflated(Inflater::new(file, location)?),
+        })
+    }
+}
+
+/// Deflated entry inflated while it's read, a seek backward inflates again
+/// from the start of the entry
+pub struct Inflater {
+    decoder: DeflateDecoder<Take<File>>,
+    location: Location,
+    /// Position of the decoder
+    position: u64,
+    /// Position of the next read
+    target: u64,
+}
+
+impl Inflater {
+    fn new(mut file: File, location: Location) -> Result<Self> {
+        file.seek(SeekFrom::Start(location.start))?;
+        Ok(Self {
+            decoder: DeflateDecoder::new(file.take(location.compressed_size)),
+            location,
+            position: 0,
+            target: 0,
+        })
+    }
+
+    /// Move the decoder to the position of the next read
+    fn skip(&mut self) -> Result<()> {
+        if self.target < self.position {
+            let mut file = self.decoder.get_ref().get_ref().try_clone()?;
+            file.seek(SeekFrom::Start(self.location.start))?;
+            self.decoder.reset(file.take(self.location.compressed_size));
+            self.position = 0;
+        }
+
+        let len = self.target - self.position;
+        let mut skipped = (&mut self.decoder).take(len);
+        self.position += std::io::copy(&mut skipped, &mut std::io::sink())?;
+        Ok(())
+    }
+}
+
+/// Entry of a zip archive. The stored entries (like the MP3 files) are read
+/// in place, the other ones are inflated while they are read.
+pub enum ZipEntry {
+    Stored {
+        file: File,
+        start: u64,
+        size: u64,
+        position: u64,
+    },
+    Deflated(Inflater),
+}
+
+impl Read for ZipEntry {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            ZipEntry::Stored {
+                file,
+                start,
+                size,
+                position,
+            } => {
+                /* Never read after the end of the entry */
+                let remaining = size.saturating_sub(*position);
+                let len = std::cmp::min(buf.len() as u64, remaining) as usize;
+                if len == 0 {
+                    return Ok(0);
+                }
+
+                file.seek(SeekFrom::Start(*start + *position))?;
+                let n = file.read(&mut buf[..len])?;
+                *position += n as u64;
+                Ok(n)
+            }
+            ZipEntry::Deflated(inflater) => {
+                inflater.skip()?;
+                let n = inflater.decoder.read(buf)?;
+                inflater.position += n as u64;
+                inflater.target = inflater.position;
+                Ok(n)
+            }
+        }
+    }
+}
+
+impl Seek for ZipEntry {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let (size, position) = match self {
+            ZipEntry::Stored { size, position, .. } => (*size, position),
+            /* The decoder is moved on the next read */
+            ZipEntry::Deflated(inflater) => (inflater.location.size, &mut inflater.target),
+        };
+
+        let new_pos = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::Current(n) => *position as i64 + n,
+            SeekFrom::End(n) => size as i64 + n,
+        };
+
+        *position = new_pos.max(0) as u64;
+        Ok(*position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    #[test]
+    fn entries() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let path = dir.path().join("book.zip");
+        let bytes: Vec<u8> = (0..2048).map(|i| (i % 251) as u8).collect();
+
+        let mut zip = zip::ZipWriter::new(File::create(&path).expect("cannot create zip"));
+        for (name, method) in [
+            ("stored", CompressionMethod::Stored),
+            ("deflated", CompressionMethod::Deflated),
+        ] {
+            let options = SimpleFileOptions::default().compression_method(method);
+            zip.start_file(name, options).expect("cannot start file");
+            zip.write_all(&bytes).expect("cannot write file");
+        }
+        zip.finish().expect("cannot finish zip");
+
+        let index = ZipIndex::open(&path).expect("cannot read zip");
+        assert_eq!(index.names().count(), 2);
+        for name in ["stored", "deflated"] {
+            let mut entry = index.entry(name).expect("entry not found");
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).expect("cannot read");
+            assert_eq!(content, bytes);
+
+            let mut buf = [0u8; 16];
+            entry.seek(SeekFrom::Start(1000)).expect("cannot seek");
+            entry.read_exact(&mut buf).expect("cannot read");
+            assert_eq!(buf, bytes[1000..1016]);
+
+            assert_eq!(entry.seek(SeekFrom::End(-8)).expect("cannot seek"), 2040);
+            assert_eq!(entry.read(&mut buf).expect("cannot read"), 8);
+            assert_eq!(entry.read(&mut buf).expect("cannot read"), 0);
+
+            /* Backward after a partial read */
+            entry.seek(SeekFrom::Start(100)).expect("cannot seek");
+            entry.read_exact(&mut buf).expect("cannot read");
+            entry.seek(SeekFrom::Current(-20)).expect("cannot seek");
+            entry.read_exact(&mut buf).expect("cannot read");
+            assert_eq!(buf, bytes[96..112]);
+        }
+
+        assert!(index.entry("missing").is_err());
+    }
+}
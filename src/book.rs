@@ -16,9 +16,21 @@
  */
 
 pub mod book;
+pub mod cache;
+pub mod check;
+pub mod graph;
+pub mod info;
+pub mod loudness;
 pub mod story_archive;
 pub mod story_fs;
+pub mod story_zip;
 
 pub use book::Book;
 pub use book::ControlSettings;
+pub use book::Source;
 pub use book::Stage;
+pub use check::Issue;
+pub use check::Severity;
+pub use graph::GraphFormat;
+pub use info::BookInfo;
+pub use loudness::Loudness;
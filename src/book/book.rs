@@ -17,23 +17,30 @@
 
 use anyhow::Result;
 use rand::Rng;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fs::File,
     path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
 };
 
-use crate::decrypt::{DecryptedFile, FileReader};
+use crate::archive::ZipIndex;
+use crate::decrypt::{Cipher, DecryptedFile, FileReader};
+use crate::state::Position;
 
-#[derive(Deserialize, Debug)]
+/// Max number of positions kept for the back action
+const HISTORY_SIZE: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Transition {
     pub(super) action_node: String,
     pub(super) option_index: isize,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ControlSettings {
     pub wheel: bool,
@@ -43,10 +50,11 @@ pub struct ControlSettings {
     pub autoplay: bool,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub(super) struct StageNode {
     pub(super) uuid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub(super) square_one: Option<bool>,
     pub(super) image: Option<String>,
     pub(super) audio: Option<String>,
@@ -55,18 +63,22 @@ pub(super) struct StageNode {
     pub(super) control_settings: ControlSettings,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub(super) struct ActionNode {
     pub(super) id: String,
     pub(super) options: Vec<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub(super) struct Story {
     pub(super) format: String,
     pub(super) version: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) description: Option<String>,
     pub(super) night_mode_available: bool,
     pub(super) stage_nodes: Vec<StageNode>,
     pub(super) action_nodes: Vec<ActionNode>,
@@ -74,7 +86,18 @@ pub(super) struct Story {
 
 #[derive(Debug)]
 pub struct Book {
-    pub(super) encrypted: bool,
+    pub(super) id: String,
+    /// Pack identifier (the square one stage node or the Lunii metadata)
+    pub(super) uuid: Option<String>,
+    pub(super) path: PathBuf,
+    /// Cipher of the assets (None for plain files)
+    pub(super) cipher: Option<Cipher>,
+    /// Zip archive of the assets (the assets paths are the entries names)
+    pub(super) zip: Option<PathBuf>,
+    /// Entries of the zip archive, read on the first use
+    pub(super) zip_index: OnceLock<ZipIndex>,
+    /// Loudness gains of the audio assets, read once the book is analyzed
+    pub(super) gains: OnceLock<HashMap<String, f32>>,
 
     pub(super) images_path: PathBuf,
     pub(super) audio_path: PathBuf,
@@ -87,6 +110,8 @@ pub struct Book {
     pub(super) current_stage_node: Option<String>,
     pub(super) current_action_node: Option<String>,
     pub(super) current_action_index: usize,
+
+    pub(super) history: VecDeque<Position>,
 }
 
 #[derive(Debug)]
@@ -97,6 +122,13 @@ pub struct Stage {
     pub audio: Option<String>,
 }
 
+impl Stage {
+    /// A story is a stage where the audio can be paused
+    pub fn is_story(&self) -> bool {
+        self.control_settings.pause
+    }
+}
+
 enum ActionButtons {
     Ok,
     Home,
@@ -105,13 +137,45 @@ enum ActionButtons {
 enum ActionWheel {
     Right,
     Left,
+    Index(usize),
 }
 
 pub enum Source<'a> {
     StoryArchive(&'a Path),
+    StoryZip(&'a Path),
     StoryFs(&'a Path),
 }
 
+impl<'a> Source<'a> {
+    pub fn path(&self) -> &'a Path {
+        match self {
+            Source::StoryArchive(path) | Source::StoryZip(path) | Source::StoryFs(path) => path,
+        }
+    }
+
+    /// Identifier of the book without parsing it (the zip extension is dropped)
+    pub fn id(&self) -> String {
+        let name = match self {
+            Source::StoryZip(path) => path.file_stem(),
+            _ => self.path().file_name(),
+        };
+        name.unwrap_or_default().to_string_lossy().to_string()
+    }
+
+    /// Detect the kind of book in a directory
+    pub fn detect(path: &'a Path) -> Option<Self> {
+        if Book::is_story_archive(path) {
+            Some(Source::StoryArchive(path))
+        } else if Book::is_story_zip(path) {
+            Some(Source::StoryZip(path))
+        } else if Book::is_story_fs(path) {
+            Some(Source::StoryFs(path))
+        } else {
+            None
+        }
+    }
+}
+
 impl Book {
     fn stage_node_get(&self) -> Option<&StageNode> {
         let uuid = self.current_stage_node.as_ref()?;
@@ -158,6 +222,14 @@ impl Book {
         let next_stage_uuid = action_node.options.get(option_index)?.clone();
         let action_node_id = action_node.id.clone();
 
+        /* The home transitions are leaving the current path, there is nothing
+         * to go back to.
+         */
+        match button {
+            ActionButtons::Ok => self.history_push(),
+            ActionButtons::Home => self.history.clear(),
+        }
+
         self.current_action_node = Some(action_node_id);
         self.current_action_index = option_index;
         self.current_stage_node = Some(next_stage_uuid);
@@ -175,6 +247,7 @@ impl Book {
         let mut option_index = match direction {
             ActionWheel::Left => self.current_action_index as isize - 1,
             ActionWheel::Right => self.current_action_index as isize + 1,
+            ActionWheel::Index(index) => index as isize,
         };
         if option_index >= action_node.options.len() as isize {
             option_index = 0;
@@ -190,31 +263,261 @@ impl Book {
         Some(())
     }
 
+    fn history_push(&mut self) {
+        if self.history.len() >= HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(self.position());
+    }
+
+    /// Go back to the previous stage where the user has made a choice
+    ///
+    /// The stages which are played automatically (like the transitions
+    /// between a menu and a story) are skipped.
+    pub fn back(&mut self) -> Option<()> {
+        while let Some(position) = self.history.pop_back() {
+            let Some(stage_node) = position
+                .stage_node
+                .as_ref()
+                .and_then(|uuid| self.stages.get(uuid))
+                .and_then(|index| self.story.stage_nodes.get(*index))
+            else {
+                continue;
+            };
+
+            let control_settings = &stage_node.control_settings;
+            if control_settings.autoplay && !control_settings.wheel {
+                continue;
+            }
+
+            self.current_stage_node = position.stage_node;
+            self.current_action_node = position.action_node;
+            self.current_action_index = position.action_index;
+            return Some(());
+        }
+
+        None
+    }
+
+    /// The stories can be chained without the menus
+    pub fn night_mode_available(&self) -> bool {
+        self.story.night_mode_available
+    }
+
+    /// Go to the next story of the last menu where the user has made a
+    /// choice, like the night mode of the Lunii. It returns None when the
+    /// last story of this menu was played.
+    pub fn night_next(&mut self) -> Option<()> {
+        loop {
+            let position = self.history.pop_back()?;
+            let Some(stage_node) = position
+                .stage_node
+                .as_ref()
+                .and_then(|uuid| self.stages.get(uuid))
+                .and_then(|index| self.story.stage_nodes.get(*index))
+            else {
+                continue;
+            };
+            if !stage_node.control_settings.wheel {
+                continue;
+            }
+
+            let Some(action_node) = position
+                .action_node
+                .as_ref()
+                .and_then(|id| self.actions.get(id))
+                .and_then(|index| self.story.action_nodes.get(*index))
+            else {
+                continue;
+            };
+
+            let action_index = position.action_index + 1;
+            let Some(stage_uuid) = action_node.options.get(action_index) else {
+                continue;
+            };
+
+            self.current_stage_node = Some(stage_uuid.clone());
+            self.current_action_node = position.action_node;
+            self.current_action_index = action_index;
+            break;
+        }
+
+        /* Follow the title of the story up to the story itself */
+        for _ in 0..self.story.stage_nodes.len() {
+            if self.stage_get()?.is_story() {
+                return Some(());
+            }
+            self.button_ok()?;
+        }
+
+        None
+    }
+
+    /// Walk from the cover to a story, the option of every menu is given by
+    /// `choose` from the number of options (the history is kept like with
+    /// the buttons).
+    fn story_walk(&mut self, mut choose: impl FnMut(usize) -> Option<usize>) -> Option<()> {
+        self.stage_reset();
+
+        for _ in 0..self.story.stage_nodes.len() {
+            let stage = self.stage_get()?;
+            if stage.is_story() {
+                return Some(());
+            }
+            if !stage.square_one && stage.control_settings.wheel {
+                let action_node = self
+                    .current_action_node
+                    .as_ref()
+                    .and_then(|id| self.actions.get(id))
+                    .and_then(|index| self.story.action_nodes.get(*index))?;
+                let index = choose(action_node.options.len())?;
+                self.button_wheel(ActionWheel::Index(index))?;
+            }
+            self.button_ok()?;
+        }
+
+        None
+    }
+
+    /// Walk from the cover to a random story, a random option is chosen in
+    /// every menu.
+    pub fn random_story(&mut self) -> Option<()> {
+        self.story_walk(|len| (len > 0).then(|| rand::rng().random_range(0..len)))
+    }
+
+    /// Walk from the cover to the story given by the options chosen in the
+    /// menus (the first option is used when the path is too short).
+    pub fn story_path(&mut self, path: &[usize]) -> Option<()> {
+        let mut path = path.iter();
+        self.story_walk(|len| {
+            let index = path.next().copied().unwrap_or(0);
+            (index < len).then_some(index)
+        })
+    }
+
+    /// Jump to a stage (an uuid or the beginning of an uuid), the history
+    /// is cleared. The menu of the stage is kept for the wheel.
+    pub fn stage_select(&mut self, uuid: &str) -> Option<()> {
+        let uuid = uuid.to_lowercase();
+        let stage_node = self
+            .story
+            .stage_nodes
+            .iter()
+            .find(|stage_node| stage_node.uuid.to_lowercase().starts_with(&uuid))?;
+        let stage_uuid = stage_node.uuid.clone();
+
+        self.stage_reset();
+        if let Some((action_node, index)) = self.story.action_nodes.iter().find_map(|node| {
+            let index = node
+                .options
+                .iter()
+                .position(|option| *option == stage_uuid)?;
+            Some((node.id.clone(), index))
+        }) {
+            self.current_action_node = Some(action_node);
+            self.current_action_index = index;
+        }
+        self.current_stage_node = Some(stage_uuid);
+
+        Some(())
+    }
+
+    /// Book identifier (the folder name)
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Folder (or archive) of the book
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the current position in the book (without the audio offset
+    /// which is only known by the player)
+    pub fn position(&self) -> Position {
+        Position {
+            stage_node: self.current_stage_node.clone(),
+            action_node: self.current_action_node.clone(),
+            action_index: self.current_action_index,
+            audio_offset: Duration::ZERO,
+        }
+    }
+
+    /// Restore a position, it's ignored if the nodes are not available
+    pub fn position_restore(&mut self, position: &Position) -> Option<()> {
+        let stage_uuid = position.stage_node.as_ref()?;
+        self.stages.get(stage_uuid)?;
+
+        if let Some(id) = &position.action_node {
+            let index = self.actions.get(id)?;
+            let action_node = self.story.action_nodes.get(*index)?;
+            if action_node.options.get(position.action_index) != Some(stage_uuid) {
+                return None;
+            }
+        }
+
+        self.current_stage_node = position.stage_node.clone();
+        self.current_action_node = position.action_node.clone();
+        self.current_action_index = position.action_index;
+        self.history.clear();
+
+        Some(())
+    }
+
     /// Reset the book to the start node
     pub fn stage_reset(&mut self) {
         self.current_action_index = 0;
         self.current_stage_node = self.start_node_uuid.clone();
         self.current_action_node = None;
+        self.history.clear();
     }
 
-    /// Get the current image, audio and inputs from the stage
-    pub fn stage_get(&self) -> Option<Stage> {
-        let stage_node = self.stage_node_get()?;
-        Some(Stage {
+    fn stage_from(stage_node: &StageNode) -> Stage {
+        Stage {
             square_one: stage_node.square_one.unwrap_or(false),
             control_settings: stage_node.control_settings.clone(),
             image: stage_node.image.clone(),
             audio: stage_node.audio.clone(),
-        })
+        }
+    }
+
+    /// Get the current image, audio and inputs from the stage
+    pub fn stage_get(&self) -> Option<Stage> {
+        self.stage_node_get().map(Self::stage_from)
+    }
+
+    /// Stage reached by the OK button, without moving. It's None when the
+    /// option is random (it's only known when the button is handled).
+    pub fn ok_peek(&self) -> Option<Stage> {
+        let stage_node = self.stage_node_get()?;
+        let action_node = self.action_node_get(&ActionButtons::Ok, stage_node)?;
+        let option_index = stage_node.ok_transition.as_ref()?.option_index;
+        if option_index < 0 {
+            return None;
+        }
+
+        let uuid = action_node.options.get(option_index as usize)?;
+        let index = self.stages.get(uuid)?;
+        self.story.stage_nodes.get(*index).map(Self::stage_from)
+    }
+
+    fn file_open(&self, path: &Path) -> Result<FileReader> {
+        if self.zip.is_some() {
+            let name = path.to_string_lossy();
+            return Ok(FileReader::Zip(self.zip_index()?.entry(&name)?));
+        }
+
+        let file = match self.cipher {
+            Some(ref cipher) => FileReader::Encrypted(DecryptedFile::open_with(path, cipher)?),
+            None => FileReader::Plain(File::open(path)?),
+        };
+
+        Ok(file)
     }
 
     pub fn images_file_get(&self, image: &String) -> Result<(FileReader, image::ImageFormat)> {
         let path = &self.images_path.join(image);
-        let file = if self.encrypted {
-            FileReader::Encrypted(DecryptedFile::open(path)?)
-        } else {
-            FileReader::Plain(File::open(path)?)
-        };
+        let file = self.file_open(path)?;
 
         let format = match path.extension() {
             Some(ext) => {
@@ -234,19 +537,15 @@ impl Book {
 
     pub fn audio_file_get(&self, audio: &String) -> Result<FileReader> {
         let path = &self.audio_path.join(audio);
-        let file = if self.encrypted {
-            FileReader::Encrypted(DecryptedFile::open(path)?)
-        } else {
-            FileReader::Plain(File::open(path)?)
-        };
-
-        Ok(file)
+        self.file_open(path)
     }
 
-    pub fn from_source(source: Source) -> Result<Self> {
+    /// Load a book, the device key is required only by the Lunii v3 packs
+    pub fn from_source(source: Source, device_key: Option<&Cipher>) -> Result<Self> {
         match source {
             Source::StoryArchive(path) => Self::from_archive_file(path),
-            Source::StoryFs(path) => Self::from_fs_directory(path),
+            Source::StoryZip(path) => Self::from_zip_file(path),
+            Source::StoryFs(path) => Self::from_fs_directory(path, device_key),
         }
     }
 
@@ -0,0 +1,331 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::UNIX_EPOCH,
+};
+
+use super::book::{Book, Source, Story};
+use super::info::BookInfo;
+use super::story_archive::STORY_JSON;
+use crate::decrypt::Cipher;
+
+const THUMBNAIL: &str = "thumbnail.png";
+
+/// Files parsed to build a Lunii story FS
+const STORY_FS_FILES: [&str; 7] = ["ni", "li", "ri", "si", "md", "nm", "bt"];
+
+/// Size and modification time of the files parsed to build a book (None
+/// when a file is missing)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Fingerprint(Vec<Option<(u64, u64, u32)>>);
+
+impl Fingerprint {
+    fn new(source: &Source) -> Self {
+        let files = match source {
+            Source::StoryArchive(path) => vec![path.join(STORY_JSON)],
+            Source::StoryZip(path) => vec![path.to_path_buf()],
+            Source::StoryFs(path) => STORY_FS_FILES.iter().map(|name| path.join(name)).collect(),
+        };
+
+        Self(
+            files
+                .iter()
+                .map(|file| {
+                    let metadata = fs::metadata(file).ok()?;
+                    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+                    Some((metadata.len(), modified.as_secs(), modified.subsec_nanos()))
+                })
+                .collect(),
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedInfo {
+    fingerprint: Fingerprint,
+    info: BookInfo,
+}
+
+/// Gain of the audio assets for the loudness normalization
+#[derive(Serialize, Deserialize)]
+struct CachedGains {
+    fingerprint: Fingerprint,
+    gains: HashMap<String, f32>,
+}
+
+/// Everything needed to rebuild a book, the cipher is never written
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedBook {
+    fingerprint: Fingerprint,
+    id: String,
+    uuid: Option<String>,
+    zip: Option<PathBuf>,
+    images_path: PathBuf,
+    audio_path: PathBuf,
+    encrypted: bool,
+    story: Story,
+}
+
+/// Each book has its own folder in the cache
+fn cache_file(cache_dir: &Path, source: &Source, name: &str) -> Option<PathBuf> {
+    let book = source.path().file_name()?;
+    Some(cache_dir.join(book).join(name))
+}
+
+/// Temporary file next to a cache file, it's unique because the same book
+/// can be written by the loudness analysis or by the admin tool.
+fn tmp_file(path: &Path) -> PathBuf {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    path.with_extension(format!("{}.{count}.tmp", process::id()))
+}
+
+/// Write in a temporary file and rename it, like the state
+fn file_write(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> Result<()>) -> Result<()> {
+    fs::create_dir_all(path.parent().context("Missing cache folder")?)?;
+    let tmp = tmp_file(path);
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    let written = write(&mut file).and_then(|()| Ok(file.flush()?));
+    if let Err(e) = written {
+        let _ = fs::remove_file(tmp);
+        return Err(e);
+    }
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+fn cache_read<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let file = File::open(path).ok()?;
+    serde_json::from_reader(BufReader::new(file)).ok()
+}
+
+fn cache_write<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    file_write(path, |file| Ok(serde_json::to_writer(file, value)?))
+}
+
+impl Book {
+    /// Metadata of a book from the cache, None if the book has changed
+    pub fn info_cached(source: &Source, cache_dir: &Path) -> Option<BookInfo> {
+        let cached: CachedInfo = cache_read(&cache_file(cache_dir, source, "info.json")?)?;
+        (cached.fingerprint == Fingerprint::new(source)).then_some(cached.info)
+    }
+
+    /// Gains of the audio assets from the cache, None if the book has
+    /// changed or was never analyzed
+    pub fn gains_cached(source: &Source, cache_dir: &Path) -> Option<HashMap<String, f32>> {
+        let cached: CachedGains = cache_read(&cache_file(cache_dir, source, "gain.json")?)?;
+        (cached.fingerprint == Fingerprint::new(source)).then_some(cached.gains)
+    }
+
+    /// Write the gains of the audio assets in the cache
+    pub fn gains_write(&self, cache_dir: &Path, gains: HashMap<String, f32>) -> Result<()> {
+        let source = Source::detect(&self.path).context("Not a book")?;
+
+        let cached = CachedGains {
+            fingerprint: Fingerprint::new(&source),
+            gains,
+        };
+        let path = cache_file(cache_dir, &source, "gain.json").context("Missing book name")?;
+        cache_write(&path, &cached)
+    }
+
+    /// Extract the thumbnail of a zip archive in the cache (again if the
+    /// archive is newer), None without thumbnail or if it's not a zip
+    pub fn thumbnail_cache(&self, cache_dir: &Path) -> Result<Option<PathBuf>> {
+        let Some(ref zip) = self.zip else {
+            return Ok(None);
+        };
+        /* The thumbnail is next to story.json */
+        let name = self.images_path.with_file_name(THUMBNAIL);
+        let name = name.to_string_lossy();
+        let index = self.zip_index()?;
+        if !index.names().any(|entry| *entry == name) {
+            return Ok(None);
+        }
+
+        let source = Source::detect(&self.path).context("Not a book")?;
+        let path = cache_file(cache_dir, &source, THUMBNAIL).context("Missing book name")?;
+        let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+        if let (Ok(cached), Ok(archive)) = (modified(&path), modified(zip))
+            && cached >= archive
+        {
+            return Ok(Some(path));
+        }
+
+        let mut entry = index.entry(&name)?;
+        file_write(&path, |file| {
+            io::copy(&mut entry, file)?;
+            Ok(())
+        })?;
+        Ok(Some(path))
+    }
+
+    /// Book from the cache, None if the book has changed. The cached books
+    /// were already checked.
+    pub fn from_cache(
+        source: &Source,
+        device_key: Option<&Cipher>,
+        cache_dir: &Path,
+    ) -> Option<Self> {
+        let cached: CachedBook = cache_read(&cache_file(cache_dir, source, "book.json")?)?;
+        if cached.fingerprint != Fingerprint::new(source) {
+            return None;
+        }
+
+        let path = source.path();
+        let cipher = match cached.encrypted {
+            true => Some(Self::fs_cipher(path, device_key).ok()?),
+            false => None,
+        };
+
+        let mut book = Self::from_story(cached.id, path, &cached.images_path, cached.story);
+        book.uuid = cached.uuid;
+        book.cipher = cipher;
+        book.zip = cached.zip;
+        book.audio_path = cached.audio_path;
+        Some(book)
+    }
+
+    /// Write the book and its metadata in the cache
+    pub fn cache_write(&self, cache_dir: &Path) -> Result<()> {
+        let source = Source::detect(&self.path).context("Not a book")?;
+        let fingerprint = Fingerprint::new(&source);
+
+        let cached = CachedBook {
+            fingerprint: fingerprint.clone(),
+            id: self.id.clone(),
+            uuid: self.uuid.clone(),
+            zip: self.zip.clone(),
+            images_path: self.images_path.clone(),
+            audio_path: self.audio_path.clone(),
+            encrypted: self.cipher.is_some(),
+            story: self.story.clone(),
+        };
+        let path = cache_file(cache_dir, &source, "book.json").context("Missing book name")?;
+        cache_write(&path, &cached)?;
+
+        let mut info = self.info();
+        if info.thumbnail.is_none() {
+            info.thumbnail = self.thumbnail_cache(cache_dir).ok().flatten();
+        }
+        let cached = CachedInfo { fingerprint, info };
+        let path = cache_file(cache_dir, &source, "info.json").context("Missing book name")?;
+        cache_write(&path, &cached)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::story_archive::tests::story_write;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn cache() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let cache_dir = dir.path().join("cache");
+        let src = dir.path().join("src");
+        let dest = dir.path().join("2643948D");
+        story_write(&src, |_| {});
+
+        let book = Book::from_archive_file(&src).expect("story.json not found");
+        book.to_fs_directory(&dest).expect("cannot write story fs");
+
+        for path in [&src, &dest] {
+            let source = Source::detect(path).expect("book not found");
+            assert!(Book::info_cached(&source, &cache_dir).is_none());
+            assert!(Book::from_cache(&source, None, &cache_dir).is_none());
+
+            let book = Book::from_source(source, None).expect("cannot load book");
+            book.cache_write(&cache_dir).expect("cannot write cache");
+
+            let source = Source::detect(path).expect("book not found");
+            let info = Book::info_cached(&source, &cache_dir).expect("info not cached");
+            assert_eq!(info, book.info());
+            let cached = Book::from_cache(&source, None, &cache_dir).expect("book not cached");
+            assert_eq!(cached.info(), book.info());
+            assert_eq!(cached.stages, book.stages);
+            assert_eq!(cached.check(), book.check());
+            assert_eq!(cached.cipher.is_some(), book.cipher.is_some());
+            assert!(cached.stage_get().is_some());
+
+            let source = Source::detect(path).expect("book not found");
+            assert!(Book::gains_cached(&source, &cache_dir).is_none());
+            assert_eq!(book.loudness_gain("a.mp3", &cache_dir), 1.0);
+            let gains = HashMap::from([(String::from("a.mp3"), 0.5)]);
+            book.gains_write(&cache_dir, gains.clone())
+                .expect("cannot write gains");
+            assert_eq!(Book::gains_cached(&source, &cache_dir), Some(gains));
+
+            /* The gains are read once */
+            assert_eq!(book.loudness_gain("a.mp3", &cache_dir), 0.5);
+            let path = cache_file(&cache_dir, &source, "gain.json").unwrap();
+            fs::remove_file(path).expect("cannot remove gains");
+            assert_eq!(book.loudness_gain("a.mp3", &cache_dir), 0.5);
+        }
+
+        /* A changed book is parsed again */
+        let story = src.join(STORY_JSON);
+        let modified = SystemTime::now() + Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(&story)
+            .and_then(|file| file.set_modified(modified))
+            .expect("cannot set the date");
+        let source = Source::detect(&src).expect("book not found");
+        assert!(Book::info_cached(&source, &cache_dir).is_none());
+        assert!(Book::from_cache(&source, None, &cache_dir).is_none());
+        assert!(Book::gains_cached(&source, &cache_dir).is_none());
+    }
+
+    #[test]
+    fn cache_names() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let cache_dir = dir.path().join("cache");
+
+        /* The cache of a book is not the one of another book */
+        for (name, title) in [("x", "X"), ("x.info", "X info")] {
+            let path = dir.path().join(name);
+            story_write(&path, |story| story["title"] = title.into());
+            let book = Book::from_archive_file(&path).expect("story.json not found");
+            book.cache_write(&cache_dir).expect("cannot write cache");
+        }
+        for (name, title) in [("x", "X"), ("x.info", "X info")] {
+            let path = dir.path().join(name);
+            let source = Source::detect(&path).expect("book not found");
+            let info = Book::info_cached(&source, &cache_dir).expect("info not cached");
+            assert_eq!(info.title.as_deref(), Some(title));
+            assert!(Book::from_cache(&source, None, &cache_dir).is_some());
+        }
+
+        /* Each write has its own temporary file */
+        let path = cache_dir.join("x").join("info.json");
+        assert_ne!(tmp_file(&path), tmp_file(&path));
+        let tmp = tmp_file(&path);
+        assert_eq!(tmp.parent(), path.parent());
+    }
+}
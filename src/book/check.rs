@@ -0,0 +1,422 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt,
+};
+
+use super::book::{Book, StageNode, Transition};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The book can be read but something is wrong in the story
+    Warning,
+    /// The book is broken and will fail at runtime
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    MissingSquareOne,
+    DuplicateStageNode(String),
+    DuplicateActionNode(String),
+    MissingActionNode {
+        stage: String,
+        action: String,
+    },
+    EmptyActionNode(String),
+    OptionOutOfRange {
+        stage: String,
+        action: String,
+        index: isize,
+    },
+    MissingStageNode {
+        action: String,
+        stage: String,
+    },
+    MissingAsset {
+        stage: String,
+        asset: String,
+    },
+    Unreachable(String),
+    DeadEnd(String),
+    Trap(Vec<String>),
+}
+
+impl Issue {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Issue::Unreachable(_) | Issue::DeadEnd(_) | Issue::Trap(_) => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::MissingSquareOne => write!(f, "no stage node with squareOne"),
+            Issue::DuplicateStageNode(uuid) => write!(f, "duplicate stage node {uuid}"),
+            Issue::DuplicateActionNode(id) => write!(f, "duplicate action node {id}"),
+            Issue::MissingActionNode { stage, action } => {
+                write!(
+                    f,
+                    "stage node {stage} uses the missing action node {action}"
+                )
+            }
+            Issue::EmptyActionNode(id) => write!(f, "action node {id} has no options"),
+            Issue::OptionOutOfRange {
+                stage,
+                action,
+                index,
+            } => write!(
+                f,
+                "stage node {stage} uses the option {index} out of range of the action node {action}"
+            ),
+            Issue::MissingStageNode { action, stage } => {
+                write!(
+                    f,
+                    "action node {action} uses the missing stage node {stage}"
+                )
+            }
+            Issue::MissingAsset { stage, asset } => {
+                write!(f, "stage node {stage} uses the missing asset {asset}")
+            }
+            Issue::Unreachable(uuid) => write!(f, "stage node {uuid} is unreachable"),
+            Issue::DeadEnd(uuid) => write!(f, "stage node {uuid} is a dead end"),
+            Issue::Trap(uuids) => write!(
+                f,
+                "stage nodes {} are a cycle without exit",
+                uuids.join(", ")
+            ),
+        }
+    }
+}
+
+impl Book {
+    fn transition_check(
+        &self,
+        issues: &mut Vec<Issue>,
+        stage_node: &StageNode,
+        transition: &Option<Transition>,
+    ) {
+        let Some(transition) = transition else {
+            return;
+        };
+
+        let Some(index) = self.actions.get(&transition.action_node) else {
+            issues.push(Issue::MissingActionNode {
+                stage: stage_node.uuid.clone(),
+                action: transition.action_node.clone(),
+            });
+            return;
+        };
+
+        let action_node = &self.story.action_nodes[*index];
+        if transition.option_index >= action_node.options.len() as isize {
+            issues.push(Issue::OptionOutOfRange {
+                stage: stage_node.uuid.clone(),
+                action: action_node.id.clone(),
+                index: transition.option_index,
+            });
+        }
+    }
+
+    /// Stages reachable from a transition (the wheel can select all the
+    /// options when it's enabled on the selected stage).
+    fn transition_targets(&self, transition: &Transition) -> Vec<usize> {
+        let Some(action_node) = self
+            .actions
+            .get(&transition.action_node)
+            .and_then(|index| self.story.action_nodes.get(*index))
+        else {
+            return Vec::new();
+        };
+
+        let options: Vec<usize> = action_node
+            .options
+            .iter()
+            .filter_map(|uuid| self.stages.get(uuid).copied())
+            .collect();
+
+        if transition.option_index < 0 {
+            return options;
+        }
+
+        let Some(selected) = action_node
+            .options
+            .get(transition.option_index as usize)
+            .and_then(|uuid| self.stages.get(uuid))
+        else {
+            return Vec::new();
+        };
+
+        if self.story.stage_nodes[*selected].control_settings.wheel {
+            options
+        } else {
+            vec![*selected]
+        }
+    }
+
+    /// Stages reachable from a stage, when a transition is missing, the
+    /// book is reset to the start node.
+    fn successors(&self, index: usize, start: usize) -> Vec<usize> {
+        let stage_node = &self.story.stage_nodes[index];
+        let control_settings = &stage_node.control_settings;
+        let mut successors = Vec::new();
+
+        if control_settings.ok || control_settings.autoplay {
+            match &stage_node.ok_transition {
+                Some(transition) => successors.extend(self.transition_targets(transition)),
+                None => successors.push(start),
+            }
+        }
+
+        if control_settings.home {
+            match &stage_node.home_transition {
+                Some(transition) => successors.extend(self.transition_targets(transition)),
+                None => successors.push(start),
+            }
+        }
+
+        successors
+    }
+
+    fn reachable(&self, from: usize, graph: &[Vec<usize>]) -> HashSet<usize> {
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::from([from]);
+
+        while let Some(index) = queue.pop_front() {
+            for next in &graph[index] {
+                if visited.insert(*next) {
+                    queue.push_back(*next);
+                }
+            }
+        }
+
+        visited
+    }
+
+    fn graph_check(&self, issues: &mut Vec<Issue>, start: usize) {
+        let count = self.story.stage_nodes.len();
+        let graph: Vec<Vec<usize>> = (0..count)
+            .map(|index| self.successors(index, start))
+            .collect();
+
+        let mut reachable = self.reachable(start, &graph);
+        reachable.insert(start);
+
+        let mut reverse = vec![Vec::new(); count];
+        for (index, successors) in graph.iter().enumerate() {
+            for next in successors {
+                reverse[*next].push(index);
+            }
+        }
+        let mut returning = self.reachable(start, &reverse);
+        returning.insert(start);
+
+        let mut trap = Vec::new();
+
+        for (index, stage_node) in self.story.stage_nodes.iter().enumerate() {
+            if !reachable.contains(&index) {
+                issues.push(Issue::Unreachable(stage_node.uuid.clone()));
+                continue;
+            }
+
+            if graph[index].is_empty() {
+                issues.push(Issue::DeadEnd(stage_node.uuid.clone()));
+                continue;
+            }
+
+            /* It's not possible to go back to the cover from this stage, and
+             * the stage is part of a cycle.
+             */
+            if !returning.contains(&index) && self.reachable(index, &graph).contains(&index) {
+                trap.push(stage_node.uuid.clone());
+            }
+        }
+
+        if !trap.is_empty() {
+            issues.push(Issue::Trap(trap));
+        }
+    }
+
+    /// Validate the story graph and the assets
+    pub fn check(&self) -> Vec<Issue> {
+        let mut issues = Vec::new();
+
+        let mut uuids = HashSet::new();
+        for stage_node in &self.story.stage_nodes {
+            if !uuids.insert(&stage_node.uuid) {
+                issues.push(Issue::DuplicateStageNode(stage_node.uuid.clone()));
+            }
+        }
+
+        let mut ids = HashSet::new();
+        for action_node in &self.story.action_nodes {
+            if !ids.insert(&action_node.id) {
+                issues.push(Issue::DuplicateActionNode(action_node.id.clone()));
+            }
+            if action_node.options.is_empty() {
+                issues.push(Issue::EmptyActionNode(action_node.id.clone()));
+            }
+            for uuid in &action_node.options {
+                if !self.stages.contains_key(uuid) {
+                    issues.push(Issue::MissingStageNode {
+                        action: action_node.id.clone(),
+                        stage: uuid.clone(),
+                    });
+                }
+            }
+        }
+
+        let zip_entries = self.zip_entries();
+
+        for stage_node in &self.story.stage_nodes {
+            self.transition_check(&mut issues, stage_node, &stage_node.ok_transition);
+            self.transition_check(&mut issues, stage_node, &stage_node.home_transition);
+
+            let assets = [
+                (&self.images_path, &stage_node.image),
+                (&self.audio_path, &stage_node.audio),
+            ];
+            for (path, asset) in assets {
+                let Some(asset) = asset else {
+                    continue;
+                };
+                let path = path.join(asset);
+                let exists = match zip_entries {
+                    Some(ref entries) => entries.contains(path.to_string_lossy().as_ref()),
+                    None => path.try_exists().unwrap_or_default(),
+                };
+                if !exists {
+                    issues.push(Issue::MissingAsset {
+                        stage: stage_node.uuid.clone(),
+                        asset: asset.clone(),
+                    });
+                }
+            }
+        }
+
+        let start = self
+            .start_node_uuid
+            .as_ref()
+            .and_then(|uuid| self.stages.get(uuid));
+        match start {
+            Some(start) => self.graph_check(&mut issues, *start),
+            None => issues.push(Issue::MissingSquareOne),
+        }
+
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::story_archive::tests::story_write;
+    use serde_json::Value;
+
+    #[test]
+    fn valid() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        story_write(dir.path(), |_| {});
+
+        /* The last stage node is not used by the test story */
+        let book = Book::from_archive_file(dir.path()).expect("story.json not found");
+        assert_eq!(
+            book.check(),
+            vec![Issue::Unreachable(String::from(
+                "5ecc8e40-9f2b-4d21-82b8-e04c251f2633"
+            ))]
+        );
+    }
+
+    #[test]
+    fn broken() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        story_write(dir.path(), |story| {
+            let stage_nodes = story["stageNodes"].as_array_mut().unwrap();
+            stage_nodes[1]["okTransition"]["actionNode"] = Value::from("missing");
+            stage_nodes[2]["okTransition"]["optionIndex"] = Value::from(3);
+            stage_nodes[3]["image"] = Value::from("missing.png");
+            stage_nodes[4]["uuid"] = stage_nodes[5]["uuid"].clone();
+        });
+
+        let book = Book::from_archive_file(dir.path()).expect("story.json not found");
+        let issues = book.check();
+        assert!(issues.contains(&Issue::DuplicateStageNode(String::from(
+            "e8ebb46f-9608-439d-a7cf-ff90f33bf4c8"
+        ))));
+        assert!(issues.contains(&Issue::MissingActionNode {
+            stage: String::from("ef895f69-6f4e-48a5-ad3b-3ed12c8c4608"),
+            action: String::from("missing"),
+        }));
+        assert!(issues.contains(&Issue::OptionOutOfRange {
+            stage: String::from("cd8566b9-b700-4694-9ea5-212ffe0e6e8e"),
+            action: String::from("e1204f8a-a39c-4de6-928b-491a6d4d0b2a"),
+            index: 3,
+        }));
+        assert!(issues.contains(&Issue::MissingAsset {
+            stage: String::from("3fc1414a-f281-4d2a-9ddc-1cb15feeabae"),
+            asset: String::from("missing.png"),
+        }));
+        assert!(
+            issues
+                .iter()
+                .any(|issue| issue.severity() == Severity::Warning)
+        );
+    }
+
+    #[test]
+    fn missing_square_one() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        story_write(dir.path(), |story| {
+            story["stageNodes"][0]["squareOne"] = Value::Null;
+        });
+
+        let book = Book::from_archive_file(dir.path()).expect("story.json not found");
+        assert_eq!(book.check(), vec![Issue::MissingSquareOne]);
+    }
+
+    #[test]
+    fn trap() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        story_write(dir.path(), |story| {
+            /* A story loops on itself without HOME */
+            let stage_nodes = story["stageNodes"].as_array_mut().unwrap();
+            let stage_node = &mut stage_nodes[14];
+            stage_node["controlSettings"]["home"] = Value::from(false);
+            stage_node["okTransition"] = serde_json::json!({
+                "actionNode": "loop",
+                "optionIndex": 0
+            });
+            let uuid = stage_node["uuid"].clone();
+            story["actionNodes"]
+                .as_array_mut()
+                .unwrap()
+                .push(serde_json::json!({ "id": "loop", "options": [uuid] }));
+        });
+
+        let book = Book::from_archive_file(dir.path()).expect("story.json not found");
+        let issues = book.check();
+        assert!(issues.contains(&Issue::Trap(vec![String::from(
+            "2049a24b-80f9-4016-90e1-8ff2f689c5b9"
+        )])));
+    }
+}
@@ -0,0 +1,198 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use clap::ValueEnum;
+use std::fmt::Write;
+
+use super::book::{Book, ControlSettings, StageNode, Transition};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum GraphFormat {
+    /// Graphviz
+    Dot,
+    /// Mermaid flowchart
+    Mermaid,
+}
+
+fn short(id: &str) -> &str {
+    id.get(..8).unwrap_or(id)
+}
+
+fn controls(control_settings: &ControlSettings) -> String {
+    let controls = [
+        (control_settings.wheel, "wheel"),
+        (control_settings.ok, "ok"),
+        (control_settings.home, "home"),
+        (control_settings.pause, "pause"),
+        (control_settings.autoplay, "autoplay"),
+    ];
+    controls
+        .iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Book {
+    /// Edge from a stage to an action node: (action index, label, random)
+    fn graph_edge(
+        &self,
+        name: &str,
+        transition: &Option<Transition>,
+    ) -> Option<(usize, String, bool)> {
+        let transition = transition.as_ref()?;
+        let action = *self.actions.get(&transition.action_node)?;
+        let random = transition.option_index < 0;
+        let label = if random {
+            format!("{name} random")
+        } else {
+            format!("{name} {}", transition.option_index)
+        };
+        Some((action, label, random))
+    }
+
+    fn graph_edges(&self, stage_node: &StageNode) -> Vec<(usize, String, bool)> {
+        [
+            self.graph_edge("ok", &stage_node.ok_transition),
+            self.graph_edge("home", &stage_node.home_transition),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    fn graph_dot(&self) -> String {
+        let mut out = String::from("digraph story {\n");
+
+        for (i, stage_node) in self.story.stage_nodes.iter().enumerate() {
+            let square_one = stage_node.square_one.unwrap_or(false);
+            let _ = writeln!(
+                out,
+                "  s{i} [shape=box{}, label=\"{}\\n{}\"];",
+                if square_one { ", peripheries=2" } else { "" },
+                short(&stage_node.uuid),
+                controls(&stage_node.control_settings),
+            );
+        }
+
+        for (i, action_node) in self.story.action_nodes.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "  a{i} [shape=ellipse, label=\"{}\"];",
+                short(&action_node.id)
+            );
+        }
+
+        for (i, stage_node) in self.story.stage_nodes.iter().enumerate() {
+            for (action, label, random) in self.graph_edges(stage_node) {
+                let style = if random { ", style=dashed" } else { "" };
+                let _ = writeln!(out, "  s{i} -> a{action} [label=\"{label}\"{style}];");
+            }
+        }
+
+        for (i, action_node) in self.story.action_nodes.iter().enumerate() {
+            for (option, uuid) in action_node.options.iter().enumerate() {
+                if let Some(stage) = self.stages.get(uuid) {
+                    let _ = writeln!(out, "  a{i} -> s{stage} [label=\"{option}\"];");
+                }
+            }
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    fn graph_mermaid(&self) -> String {
+        let mut out = String::from("flowchart TD\n");
+
+        for (i, stage_node) in self.story.stage_nodes.iter().enumerate() {
+            let square_one = stage_node.square_one.unwrap_or(false);
+            let (open, close) = if square_one { ("[[", "]]") } else { ("[", "]") };
+            let _ = writeln!(
+                out,
+                "  s{i}{open}\"{}<br/>{}\"{close}",
+                short(&stage_node.uuid),
+                controls(&stage_node.control_settings),
+            );
+        }
+
+        for (i, action_node) in self.story.action_nodes.iter().enumerate() {
+            let _ = writeln!(out, "  a{i}((\"{}\"))", short(&action_node.id));
+        }
+
+        for (i, stage_node) in self.story.stage_nodes.iter().enumerate() {
+            for (action, label, random) in self.graph_edges(stage_node) {
+                let arrow = if random { "-.->" } else { "-->" };
+                let _ = writeln!(out, "  s{i} {arrow}|{label}| a{action}");
+            }
+        }
+
+        for (i, action_node) in self.story.action_nodes.iter().enumerate() {
+            for (option, uuid) in action_node.options.iter().enumerate() {
+                if let Some(stage) = self.stages.get(uuid) {
+                    let _ = writeln!(out, "  a{i} -->|{option}| s{stage}");
+                }
+            }
+        }
+
+        out
+    }
+
+    /// Export the story graph (stage nodes, action nodes and transitions)
+    pub fn graph(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => self.graph_dot(),
+            GraphFormat::Mermaid => self.graph_mermaid(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn dot() {
+        let book = Book::from_archive_file(Path::new("test")).expect("story.json not found");
+        let dot = book.graph(GraphFormat::Dot);
+
+        assert!(dot.starts_with("digraph story {\n"));
+        assert!(dot.contains("  s0 [shape=box, peripheries=2, label=\"2F0F3109\\nwheel ok\"];\n"));
+        assert!(dot.contains("  s3 -> a0 [label=\"ok 0\"];\n"));
+        assert!(dot.contains("  s3 -> a9 [label=\"home 0\"];\n"));
+        assert!(dot.contains("  a4 -> s8 [label=\"2\"];\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+
+    #[test]
+    fn mermaid() {
+        let book = Book::from_archive_file(Path::new("test")).expect("story.json not found");
+        let mermaid = book.graph(GraphFormat::Mermaid);
+
+        assert!(mermaid.starts_with("flowchart TD\n"));
+        assert!(mermaid.contains("  s0[[\"2F0F3109<br/>wheel ok\"]]\n"));
+        assert!(mermaid.contains("  s3 -->|ok 0| a0\n"));
+        assert!(mermaid.contains("  a4 -->|2| s8\n"));
+
+        /* One line per stage, action, transition and option */
+        let transitions = 16 + 14;
+        let options = 15;
+        assert_eq!(mermaid.lines().count(), 1 + 17 + 10 + transitions + options);
+    }
+}
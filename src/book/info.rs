@@ -0,0 +1,133 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use rodio::{Decoder, Source};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, io::BufReader, path::PathBuf, time::Duration};
+
+use super::book::Book;
+
+/// Metadata of a book, shared by the device, the CLI and the admin tool
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BookInfo {
+    /// Book identifier (the folder name)
+    pub id: String,
+    pub uuid: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub thumbnail: Option<PathBuf>,
+    pub format: String,
+    pub version: usize,
+    pub night_mode: bool,
+    pub stage_nodes: usize,
+    pub action_nodes: usize,
+    /// Total duration of the audio assets (in seconds), it's only computed
+    /// on demand because every file must be opened.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+}
+
+impl Book {
+    pub fn info(&self) -> BookInfo {
+        /* The thumbnail of a zip archive is in the cache (see `thumbnail_cache`) */
+        let thumbnail = self.path.join("thumbnail.png");
+        let thumbnail = (self.zip.is_none() && thumbnail.is_file()).then_some(thumbnail);
+
+        BookInfo {
+            id: self.id.clone(),
+            uuid: self.uuid.clone(),
+            title: self.story.title.clone(),
+            description: self.story.description.clone(),
+            thumbnail,
+            format: self.story.format.clone(),
+            version: self.story.version,
+            night_mode: self.story.night_mode_available,
+            stage_nodes: self.story.stage_nodes.len(),
+            action_nodes: self.story.action_nodes.len(),
+            duration: None,
+        }
+    }
+
+    /// Sum of the durations of the audio assets (each one is counted once)
+    pub fn duration(&self) -> Duration {
+        let audios: HashSet<&String> = self
+            .story
+            .stage_nodes
+            .iter()
+            .filter_map(|stage_node| stage_node.audio.as_ref())
+            .collect();
+
+        audios
+            .into_iter()
+            .filter_map(|audio| {
+                let mut audio = self.audio_file_get(audio).ok()?;
+                let byte_len = audio.size().ok()?;
+                Decoder::builder()
+                    .with_data(BufReader::new(audio))
+                    .with_byte_len(byte_len)
+                    .with_seekable(true)
+                    .build()
+                    .ok()?
+                    .total_duration()
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::story_archive::tests::story_write;
+    use std::{fs, path::Path};
+
+    #[test]
+    fn info() {
+        let book = Book::from_archive_file(Path::new("test")).expect("story.json not found");
+        let info = book.info();
+        assert_eq!(info.id, "test");
+        assert_eq!(
+            info.uuid.as_deref(),
+            Some("2F0F3109BFAE4E0991D7CA0C2643948D")
+        );
+        assert_eq!(info.format, "v1");
+        assert_eq!(info.stage_nodes, 17);
+        assert_eq!(info.action_nodes, 10);
+        assert!(!info.night_mode);
+
+        /* The assets are missing, nothing is counted */
+        assert_eq!(book.duration(), Duration::ZERO);
+
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let src = dir.path().join("src");
+        story_write(&src, |story| {
+            story["title"] = "Title".into();
+            story["description"] = "Description".into();
+        });
+        fs::write(src.join("thumbnail.png"), "").unwrap();
+        let info = Book::from_archive_file(&src)
+            .expect("story.json not found")
+            .info();
+        assert_eq!(info.title.as_deref(), Some("Title"));
+        assert_eq!(info.description.as_deref(), Some("Description"));
+        assert_eq!(info.thumbnail, Some(src.join("thumbnail.png")));
+
+        let json = serde_json::to_value(&info).expect("cannot serialize");
+        assert_eq!(json["stageNodes"], 17);
+        assert!(json.get("duration").is_none());
+    }
+}
@@ -0,0 +1,241 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use rodio::Decoder;
+use std::{
+    collections::{HashMap, HashSet},
+    f64::consts::PI,
+    io::BufReader,
+    path::Path,
+};
+
+use super::book::{Book, Source};
+
+/// Loudness of the normalized audio (like ReplayGain 2)
+const TARGET_LUFS: f64 = -18.0;
+/// The quiet stories are not amplified more than this
+const MAX_GAIN_DB: f64 = 12.0;
+/// Gating of EBU R128, blocks of 400 ms every 100 ms
+const SEGMENTS_PER_BLOCK: usize = 4;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+/// Integrated loudness (LUFS) and sample peak of an audio
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loudness {
+    pub lufs: f64,
+    pub peak: f32,
+}
+
+/// Second order filter (direct form I)
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// K-weighting filters of ITU-R BS.1770 for any sample rate
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    /* High shelf (head effects) */
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    /* High pass */
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// Measure the loudness of decoded samples, None for a silence
+pub fn measure<S: rodio::Source>(source: S) -> Option<Loudness> {
+    let channels = source.channels().max(1) as usize;
+    let rate = source.sample_rate() as f64;
+    let segment_len = (rate / 10.0) as usize * channels;
+
+    let mut filters: Vec<[Biquad; 2]> = (0..channels).map(|_| k_weighting(rate)).collect();
+    let mut segments = Vec::new();
+    let (mut sum, mut len, mut peak) = (0.0, 0, 0f32);
+
+    for (i, sample) in source.enumerate() {
+        peak = peak.max(sample.abs());
+        let [shelf, high_pass] = &mut filters[i % channels];
+        let weighted = high_pass.process(shelf.process(sample as f64));
+        sum += weighted * weighted;
+        len += 1;
+        if len == segment_len {
+            /* Sum of the channels (the same weight for every channel) */
+            segments.push(sum * channels as f64 / len as f64);
+            (sum, len) = (0.0, 0);
+        }
+    }
+
+    let blocks: Vec<f64> = segments
+        .windows(SEGMENTS_PER_BLOCK)
+        .map(|block| block.iter().sum::<f64>() / SEGMENTS_PER_BLOCK as f64)
+        .filter(|&power| lufs(power) > ABSOLUTE_GATE)
+        .collect();
+    if blocks.is_empty() {
+        return None;
+    }
+
+    let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+    let gate = lufs(mean(&blocks)) + RELATIVE_GATE;
+    let gated: Vec<f64> = blocks.into_iter().filter(|&p| lufs(p) > gate).collect();
+
+    Some(Loudness {
+        lufs: lufs(mean(&gated)),
+        peak,
+    })
+}
+
+impl Loudness {
+    /// Factor of the volume to reach the target loudness (without clipping)
+    pub fn gain(&self) -> f32 {
+        let db = (TARGET_LUFS - self.lufs).clamp(-MAX_GAIN_DB, MAX_GAIN_DB);
+        let gain = 10f64.powf(db / 20.0) as f32;
+        match self.peak > 0.0 {
+            true => gain.min(1.0 / self.peak),
+            false => gain,
+        }
+    }
+}
+
+impl Book {
+    /// Gain of every audio for the same loudness, it must decode every
+    /// file (slow).
+    pub fn loudness_gains(&self) -> HashMap<String, f32> {
+        let audios: HashSet<&String> = self
+            .story
+            .stage_nodes
+            .iter()
+            .filter_map(|stage_node| stage_node.audio.as_ref())
+            .collect();
+
+        audios
+            .into_iter()
+            .filter_map(|audio| {
+                let mut file = self.audio_file_get(audio).ok()?;
+                let byte_len = file.size().ok()?;
+                let decoder = Decoder::builder()
+                    .with_data(BufReader::new(file))
+                    .with_byte_len(byte_len)
+                    .build()
+                    .ok()?;
+                let loudness = measure(decoder)?;
+                Some((audio.clone(), loudness.gain()))
+            })
+            .collect()
+    }
+
+    /// Gain of an audio from the cache (1.0 if not analyzed), the gains
+    /// are read once then kept with the book
+    pub fn loudness_gain(&self, audio: &str, cache_dir: &Path) -> f32 {
+        /* Not analyzed yet, the analysis can still be running */
+        if self.gains.get().is_none()
+            && let Some(gains) =
+                Source::detect(&self.path).and_then(|source| Self::gains_cached(&source, cache_dir))
+        {
+            let _ = self.gains.set(gains);
+        }
+
+        self.gains
+            .get()
+            .and_then(|gains| gains.get(audio).copied())
+            .unwrap_or(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    fn sine(channels: u16, amplitude: f32, seconds: usize) -> SamplesBuffer {
+        let rate = 48000;
+        let samples: Vec<f32> = (0..rate * seconds)
+            .flat_map(|i| {
+                let t = i as f32 / rate as f32;
+                let sample = amplitude * (2.0 * std::f32::consts::PI * 1000.0 * t).sin();
+                std::iter::repeat_n(sample, channels as usize)
+            })
+            .collect();
+        SamplesBuffer::new(channels, rate as u32, samples)
+    }
+
+    #[test]
+    fn loudness() {
+        /* EBU Tech 3341, a stereo sine of 1 kHz at -23 dBFS is -23 LUFS */
+        let amplitude = 10f32.powf(-23.0 / 20.0);
+        let loudness = measure(sine(2, amplitude, 5)).expect("no loudness");
+        assert!((loudness.lufs + 23.0).abs() < 0.1, "{loudness:?}");
+        assert!((loudness.peak - amplitude).abs() < 1e-3);
+        assert!((loudness.gain() - 10f32.powf(5.0 / 20.0)).abs() < 0.02);
+
+        /* The gain never clips */
+        let loudness = measure(sine(1, 0.9, 2)).expect("no loudness");
+        assert!(loudness.gain() <= 1.0 / 0.9);
+        let loudness = Loudness {
+            lufs: -60.0,
+            peak: 0.01,
+        };
+        assert!((loudness.gain() - 10f32.powf(12.0 / 20.0)).abs() < 1e-3);
+
+        assert_eq!(measure(sine(2, 0.0, 2)), None);
+    }
+}
@@ -15,13 +15,35 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use anyhow::Result;
-use std::{fs::File, io::BufReader, path::Path};
+use anyhow::{Context, Result, bail};
+use image::ImageFormat;
+use rodio::{Decoder, Source};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+    process::{Command, Stdio},
+    sync::OnceLock,
+};
 
 use super::book::Book;
 use super::book::Story;
 
-const STORY_JSON: &str = "story.json";
+pub(super) const STORY_JSON: &str = "story.json";
+/// Encoder of the OGG transcoding (vorbis-tools), the decoded audio is
+/// given as raw 16-bit samples
+const OGG_ENCODER: &str = "oggenc";
+
+/// STUdio assets are flat, the Lunii names (like `000/0000001`) are flattened
+/// and the extension is added when missing.
+fn asset_name(name: &str, ext: &str) -> String {
+    let name = name.replace('/', "_");
+    match Path::new(&name).extension() {
+        Some(_) => name,
+        None => format!("{name}.{ext}"),
+    }
+}
 
 impl Book {
     pub fn is_story_archive(path: &Path) -> bool {
@@ -35,6 +57,17 @@ impl Book {
         let reader = BufReader::new(file);
         let story: Story = serde_json::from_reader(reader)?;
 
+        let id = path
+            .file_name()
+            .context("Missing folder name")?
+            .to_string_lossy()
+            .to_string();
+
+        Ok(Self::from_story(id, path, &path.join("assets"), story))
+    }
+
+    /// Book of a STUdio story, the assets are in the same folder
+    pub(super) fn from_story(id: String, path: &Path, assets: &Path, story: Story) -> Self {
         /* The first node is like the cover of the book */
         let start_node_uuid = story
             .stage_nodes
@@ -62,10 +95,16 @@ impl Book {
         let current_stage_node = start_node_uuid.clone();
         let current_action_node = None;
 
-        Ok(Self {
-            encrypted: false,
-            images_path: path.join("assets").to_path_buf(),
-            audio_path: path.join("assets").to_path_buf(),
+        Self {
+            id,
+            uuid: start_node_uuid.clone(),
+            path: path.to_path_buf(),
+            cipher: None,
+            zip: None,
+            zip_index: OnceLock::new(),
+            gains: OnceLock::new(),
+            images_path: assets.to_path_buf(),
+            audio_path: assets.to_path_buf(),
             story,
             stages,
             actions,
@@ -73,13 +112,166 @@ impl Book {
             current_stage_node,
             current_action_node,
             current_action_index,
-        })
+            history: VecDeque::new(),
+        }
+    }
+}
+
+impl Book {
+    fn image_export(&self, image: &String, assets: &Path, png: bool) -> Result<String> {
+        let (mut file, format) = self.images_file_get(image)?;
+        let ext = format.extensions_str().first().unwrap_or(&"bmp");
+        let name = asset_name(image, ext);
+
+        if png && format != ImageFormat::Png {
+            let name = Path::new(&name)
+                .with_extension("png")
+                .to_string_lossy()
+                .to_string();
+            let img = image::load(BufReader::new(file), format)?;
+            img.save_with_format(assets.join(&name), ImageFormat::Png)?;
+            return Ok(name);
+        }
+
+        io::copy(&mut file, &mut File::create(assets.join(&name))?)?;
+        Ok(name)
+    }
+
+    fn audio_export(&self, audio: &String, assets: &Path, ogg: Option<&str>) -> Result<String> {
+        let mut file = self.audio_file_get(audio)?;
+        let name = asset_name(audio, "mp3");
+
+        if let Some(encoder) = ogg {
+            let name = Path::new(&name)
+                .with_extension("ogg")
+                .to_string_lossy()
+                .to_string();
+            let byte_len = file.size()?;
+            let decoder = Decoder::builder()
+                .with_data(BufReader::new(file))
+                .with_byte_len(byte_len)
+                .build()?;
+            Self::ogg_encode(decoder, encoder, &assets.join(&name))
+                .with_context(|| format!("Cannot transcode {audio} to OGG"))?;
+            return Ok(name);
+        }
+
+        io::copy(&mut file, &mut File::create(assets.join(&name))?)?;
+        Ok(name)
+    }
+
+    /// The samples are written to the encoder while it's running
+    fn ogg_encode(source: impl Source, encoder: &str, dest: &Path) -> Result<()> {
+        let mut child = Command::new(encoder)
+            .args(["-Q", "-r", "-B", "16", "-C"])
+            .arg(source.channels().to_string())
+            .arg("-R")
+            .arg(source.sample_rate().to_string())
+            .arg("-o")
+            .arg(dest)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Cannot run {encoder}"))?;
+
+        let stdin = child.stdin.take().context("Missing encoder input")?;
+        let mut input = BufWriter::new(stdin);
+        let written = source
+            .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+            .try_for_each(|sample| input.write_all(&sample.to_le_bytes()))
+            .and_then(|()| input.flush());
+        drop(input);
+
+        let status = child.wait()?;
+        written?;
+        if !status.success() {
+            bail!("{encoder} failed: {status}");
+        }
+        Ok(())
+    }
+
+    /// Write the book as a STUdio story (story.json and the decrypted assets)
+    ///
+    /// The images can be transcoded to PNG and the audio assets to OGG (with
+    /// `oggenc`). The stage and action ids are kept, then exporting twice the
+    /// same book gives the same story.json.
+    pub fn to_archive_dir(&self, dest: &Path, png: bool, ogg: bool) -> Result<()> {
+        self.archive_dir_write(dest, png, ogg.then_some(OGG_ENCODER))
+    }
+
+    fn archive_dir_write(&self, dest: &Path, png: bool, ogg: Option<&str>) -> Result<()> {
+        if fs::exists(dest)? {
+            bail!("{:?} already exists", dest);
+        }
+
+        let assets = dest.join("assets");
+        fs::create_dir_all(&assets)?;
+
+        let mut story = self.story.clone();
+        let mut images = HashMap::new();
+        let mut audios = HashMap::new();
+
+        for stage_node in &mut story.stage_nodes {
+            if let Some(image) = &stage_node.image {
+                if !images.contains_key(image) {
+                    let name = self.image_export(image, &assets, png)?;
+                    images.insert(image.clone(), name);
+                }
+                stage_node.image = images.get(image).cloned();
+            }
+
+            if let Some(audio) = &stage_node.audio {
+                if !audios.contains_key(audio) {
+                    let name = self.audio_export(audio, &assets, ogg)?;
+                    audios.insert(audio.clone(), name);
+                }
+                stage_node.audio = audios.get(audio).cloned();
+            }
+        }
+
+        if story.title.is_none() {
+            story.title = Some(self.id.clone());
+        }
+
+        let file = File::create(dest.join(STORY_JSON))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &story)?;
+
+        let thumbnail = self.path.join("thumbnail.png");
+        if fs::exists(&thumbnail)? {
+            fs::copy(thumbnail, dest.join("thumbnail.png"))?;
+        }
+
+        Ok(())
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use serde_json::Value;
+
+    /// Copy the test story with dummy assets, the story can be changed
+    /// before to be written.
+    pub(crate) fn story_write(dir: &Path, update: impl FnOnce(&mut Value)) {
+        let story = fs::read_to_string("test/story.json").expect("story.json not found");
+        let mut story: Value = serde_json::from_str(&story).expect("invalid story.json");
+
+        let assets = dir.join("assets");
+        fs::create_dir_all(&assets).expect("cannot create assets");
+        for stage_node in story["stageNodes"].as_array().unwrap() {
+            if let Some(image) = stage_node["image"].as_str() {
+                image::RgbImage::new(4, 3)
+                    .save_with_format(assets.join(image), ImageFormat::Bmp)
+                    .expect("cannot write image");
+            }
+            if let Some(audio) = stage_node["audio"].as_str() {
+                fs::write(assets.join(audio), audio).expect("cannot write audio");
+            }
+        }
+
+        update(&mut story);
+        fs::write(dir.join(STORY_JSON), story.to_string()).expect("cannot write story");
+    }
 
     #[test]
     fn scenario() {
@@ -202,4 +394,276 @@ mod tests {
         assert_eq!(book.current_action_node, None);
         assert_eq!(book.current_stage_node, Some(current_stage_node));
     }
+
+    #[test]
+    fn position() {
+        let story = Path::new("test");
+        let mut book = Book::from_archive_file(story).expect("story.json not found");
+
+        book.button_ok().expect("OK button fail");
+        book.button_wheel_right().expect("Cannot move to option 1");
+        let position = book.position();
+
+        let mut book = Book::from_archive_file(story).expect("story.json not found");
+        book.position_restore(&position)
+            .expect("Cannot restore the position");
+        assert_eq!(book.position(), position);
+        let current_stage_node = String::from("cd8566b9-b700-4694-9ea5-212ffe0e6e8e");
+        assert_eq!(book.current_stage_node, Some(current_stage_node));
+
+        /* Unknown stage, nothing changes */
+        let mut invalid = position.clone();
+        invalid.stage_node = Some(String::from("missing"));
+        assert!(book.position_restore(&invalid).is_none());
+        assert_eq!(book.position(), position);
+
+        /* The stage must be an option of the action node */
+        let mut invalid = position.clone();
+        invalid.action_index = 0;
+        assert!(book.position_restore(&invalid).is_none());
+        assert_eq!(book.position(), position);
+    }
+
+    #[test]
+    fn back() {
+        let story = Path::new("test");
+        let mut book = Book::from_archive_file(story).expect("story.json not found");
+
+        /* Nothing before the cover */
+        assert!(book.back().is_none());
+
+        book.button_ok().expect("OK button fail");
+        book.button_wheel_right().expect("Cannot move to option 1");
+        book.button_ok().expect("OK button fail");
+        book.button_wheel_right().expect("Cannot move to option 1");
+        book.button_wheel_right().expect("Cannot move to option 2");
+        book.button_ok().expect("OK button fail");
+        book.button_ok().expect("OK button fail");
+        let current_stage_node = String::from("e643c767-d789-4bc2-b25f-71dc50d02020");
+        assert_eq!(book.current_stage_node, Some(current_stage_node));
+
+        /* The story and the transition (autoplay) are skipped */
+        book.back().expect("Cannot go back");
+        let current_action_node = String::from("e1204f8a-a39c-4de6-928b-491a6d4d0b2a");
+        let current_stage_node = String::from("0b296637-77cb-4b8b-83ee-8d5c9d9b805c");
+        assert_eq!(book.current_action_index, 2);
+        assert_eq!(book.current_action_node, Some(current_action_node));
+        assert_eq!(book.current_stage_node, Some(current_stage_node));
+
+        book.back().expect("Cannot go back");
+        let current_action_node = String::from("ff38d914-9cca-4d50-86e9-4ae6bf3e65c1");
+        let current_stage_node = String::from("cd8566b9-b700-4694-9ea5-212ffe0e6e8e");
+        assert_eq!(book.current_action_index, 1);
+        assert_eq!(book.current_action_node, Some(current_action_node));
+        assert_eq!(book.current_stage_node, Some(current_stage_node));
+
+        book.back().expect("Cannot go back");
+        let current_stage_node = String::from("2F0F3109BFAE4E0991D7CA0C2643948D");
+        assert_eq!(book.current_action_index, 0);
+        assert_eq!(book.current_action_node, None);
+        assert_eq!(book.current_stage_node, Some(current_stage_node));
+        assert!(book.back().is_none());
+
+        /* HOME leaves the path */
+        book.button_ok().expect("OK button fail");
+        book.button_ok().expect("OK button fail");
+        book.button_home().expect("HOME button fail");
+        assert!(book.back().is_none());
+    }
+
+    #[test]
+    fn night_next() {
+        let story = Path::new("test");
+        let mut book = Book::from_archive_file(story).expect("story.json not found");
+
+        /* Nothing to chain from the cover */
+        assert!(book.night_next().is_none());
+
+        book.button_ok().expect("OK button fail");
+        book.button_ok().expect("OK button fail");
+        book.button_ok().expect("OK button fail");
+        assert!(book.stage_get().expect("stage not found").is_story());
+
+        /* All the stories are chained, the menus are followed */
+        for uuid in ["1dfea263", "cf8d52a0", "3cb06916", "5ff37664", "2049a24b"] {
+            book.night_next().expect("next story not found");
+            let stage = book.stage_get().expect("stage not found");
+            assert!(stage.is_story());
+            assert!(book.current_stage_node.as_ref().unwrap().starts_with(uuid));
+        }
+        assert!(book.night_next().is_none());
+    }
+
+    #[test]
+    fn story_path() {
+        let story = Path::new("test");
+        let mut book = Book::from_archive_file(story).expect("story.json not found");
+
+        book.story_path(&[]).expect("first story not found");
+        assert!(book.stage_get().expect("stage not found").is_story());
+        let first = book.position();
+        book.story_path(&[0, 0]).expect("story not found");
+        assert_eq!(book.position(), first);
+        assert!(book.story_path(&[42]).is_none());
+
+        book.stage_select("1DFEA263").expect("stage not found");
+        assert!(book.stage_get().expect("stage not found").is_story());
+        assert!(
+            book.current_stage_node
+                .as_ref()
+                .unwrap()
+                .starts_with("1dfea263")
+        );
+        assert!(book.current_action_node.is_some());
+        assert!(book.stage_select("missing").is_none());
+    }
+
+    #[test]
+    fn ok_peek() {
+        let story = Path::new("test");
+        let mut book = Book::from_archive_file(story).expect("story.json not found");
+
+        /* Follow the OK transitions up to the first story */
+        while let Some(next) = book.ok_peek() {
+            book.button_ok().expect("ok fail");
+            let stage = book.stage_get().expect("stage not found");
+            assert_eq!(stage.audio, next.audio);
+            assert_eq!(stage.image, next.image);
+            if stage.is_story() {
+                return;
+            }
+        }
+        panic!("story not reached");
+    }
+
+    #[test]
+    fn random_story() {
+        let story = Path::new("test");
+        let mut book = Book::from_archive_file(story).expect("story.json not found");
+
+        for _ in 0..10 {
+            book.random_story().expect("random story not found");
+            assert!(book.stage_get().expect("stage not found").is_story());
+
+            /* The menus can be followed back */
+            book.back().expect("back fail");
+            assert!(!book.stage_get().expect("stage not found").is_story());
+        }
+    }
+
+    #[test]
+    fn export() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let src = dir.path().join("src");
+        let dest = dir.path().join("dest");
+        story_write(&src, |_| {});
+
+        let book = Book::from_archive_file(&src).expect("story.json not found");
+        book.to_archive_dir(&dest, true, false)
+            .expect("cannot export");
+        assert!(book.to_archive_dir(&dest, true, false).is_err());
+
+        let exported = Book::from_archive_file(&dest).expect("story.json not found");
+        assert_eq!(exported.check(), book.check());
+        assert_eq!(exported.story.title, Some(String::from("src")));
+        assert_eq!(exported.stages, book.stages);
+        assert_eq!(exported.actions, book.actions);
+
+        let image = String::from("0cd0961fd99135cb6fa6bd897b11d15262e13780.png");
+        let (_, format) = exported.images_file_get(&image).expect("image not found");
+        assert_eq!(format, ImageFormat::Png);
+
+        let audio = String::from("f9d23e73387a069e908075126a8b5df72d10b48b.mp3");
+        let mut file = exported.audio_file_get(&audio).expect("audio not found");
+        let mut content = String::new();
+        io::Read::read_to_string(&mut file, &mut content).expect("cannot read audio");
+        assert_eq!(content, audio);
+    }
+
+    /// 16-bit mono PCM in a WAV file
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        let len = samples.len() as u32 * 2;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&len.to_le_bytes());
+        for sample in samples {
+            wav.extend_from_slice(&sample.to_le_bytes());
+        }
+        wav
+    }
+
+    #[test]
+    fn export_ogg() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let src = dir.path().join("src");
+        let dest = dir.path().join("dest");
+        story_write(&src, |_| {});
+        let samples: Vec<i16> = (0..800).map(|i| (i * 40 - 16000) as i16).collect();
+        for entry in fs::read_dir(src.join("assets")).expect("cannot read assets") {
+            let path = entry.expect("cannot read assets").path();
+            if path.extension().is_some_and(|ext| ext == "mp3") {
+                fs::write(path, wav(&samples)).expect("cannot write audio");
+            }
+        }
+
+        /* The encoder keeps the raw samples and its arguments */
+        let encoder = dir.path().join("oggenc");
+        fs::write(
+            &encoder,
+            "#!/bin/sh\necho \"$@\" > \"$(dirname \"$0\")/args\"\n\
+             while [ \"$1\" != -o ]; do shift; done\ncat > \"$2\"\n",
+        )
+        .expect("cannot write encoder");
+        fs::set_permissions(&encoder, fs::Permissions::from_mode(0o755))
+            .expect("cannot set permissions");
+
+        let book = Book::from_archive_file(&src).expect("story.json not found");
+        let missing = dir.path().join("missing").to_string_lossy().to_string();
+        assert!(
+            book.archive_dir_write(&dest, false, Some(&missing))
+                .is_err()
+        );
+        fs::remove_dir_all(&dest).expect("cannot remove export");
+
+        let encoder = encoder.to_string_lossy().to_string();
+        book.archive_dir_write(&dest, false, Some(&encoder))
+            .expect("cannot export");
+        let args = fs::read_to_string(dir.path().join("args")).expect("encoder not run");
+        assert!(args.starts_with("-Q -r -B 16 -C 1 -R 8000 -o "), "{args}");
+
+        /* The audio names are changed in story.json */
+        let exported = Book::from_archive_file(&dest).expect("story.json not found");
+        assert_eq!(exported.check(), book.check());
+        let audio = String::from("f9d23e73387a069e908075126a8b5df72d10b48b.ogg");
+        assert!(exported.story.stage_nodes.iter().all(|stage_node| {
+            stage_node
+                .audio
+                .as_ref()
+                .is_none_or(|a| a.ends_with(".ogg"))
+        }));
+        let pcm = fs::read(dest.join("assets").join(&audio)).expect("audio not found");
+        let decoded: Vec<i16> = pcm
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect();
+        assert_eq!(decoded.len(), samples.len());
+        assert!(
+            decoded
+                .iter()
+                .zip(&samples)
+                .all(|(decoded, sample)| decoded.abs_diff(*sample) <= 1)
+        );
+    }
 }
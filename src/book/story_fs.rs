@@ -15,19 +15,20 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use bytemuck::{Pod, Zeroable};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fs::{self},
-    io::BufReader,
+    io::{BufReader, Cursor, Read},
     path::Path,
+    sync::OnceLock,
 };
 use uuid::Uuid;
 
 use crate::{
     FileReader,
-    decrypt::{DecryptedFile, decrypt_block},
+    decrypt::{Cipher, DecryptedFile},
 };
 
 use super::ControlSettings;
@@ -77,6 +78,17 @@ struct Ni {
     nodes: Vec<Node>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PackVersion {
+    /// XXTEA with the generic key
+    V1,
+    /// Like V1 with an authorization file (bt) bound to the device, the
+    /// packs can be encrypted with the key of the device
+    V2,
+    /// AES with the story key, this one is in bt (encrypted with the device key)
+    V3,
+}
+
 struct Li {
     list: Vec<u32>,
 }
@@ -101,12 +113,27 @@ impl Ni {
 
         Ok(Ni { header, nodes })
     }
+
+    fn version(&self, path: &Path) -> Result<PackVersion> {
+        if self.header.format_version >= 3 {
+            Ok(PackVersion::V3)
+        } else if fs::exists(path.join("bt"))? {
+            Ok(PackVersion::V2)
+        } else {
+            Ok(PackVersion::V1)
+        }
+    }
+}
+
+/// The assets names are like `000\12345678`
+fn names_valid(list: &[[u8; 12]]) -> bool {
+    list.iter().all(|name| name.starts_with(b"000\\"))
 }
 
 impl Li {
-    fn from_file(path: &Path) -> Result<Self> {
+    fn from_file(path: &Path, cipher: &Cipher) -> Result<Self> {
         let bytes = fs::read(path)?;
-        let decrypted = decrypt_block(&bytes);
+        let decrypted = cipher.decrypt_block(&bytes);
         let list: Vec<u32> = bytemuck::cast_slice(&decrypted).to_vec();
 
         Ok(Li { list })
@@ -114,9 +141,9 @@ impl Li {
 }
 
 impl Ri {
-    fn from_file(path: &Path) -> Result<Self> {
+    fn from_file(path: &Path, cipher: &Cipher) -> Result<Self> {
         let bytes = fs::read(path)?;
-        let decrypted = decrypt_block(&bytes);
+        let decrypted = cipher.decrypt_block(&bytes);
         let list: Vec<[u8; 12]> = bytemuck::cast_slice(&decrypted).to_vec();
 
         Ok(Ri { list })
@@ -124,17 +151,71 @@ impl Ri {
 }
 
 impl Si {
-    fn from_file(path: &Path) -> Result<Self> {
+    fn from_file(path: &Path, cipher: &Cipher) -> Result<Self> {
         let bytes = fs::read(path)?;
-        let decrypted = decrypt_block(&bytes);
+        let decrypted = cipher.decrypt_block(&bytes);
         let list: Vec<[u8; 12]> = bytemuck::cast_slice(&decrypted).to_vec();
 
         Ok(Si { list })
     }
 }
 
+/// Assets of a story FS (ri or si), deduplicated by name
+#[derive(Default)]
+struct Assets {
+    names: Vec<String>,
+    indexes: HashMap<String, i32>,
+}
+
+impl Assets {
+    /// Index of the asset, the 12-bytes name is `000\00000000`
+    fn index(&mut self, name: &Option<String>) -> i32 {
+        let Some(name) = name else {
+            return -1;
+        };
+
+        if let Some(index) = self.indexes.get(name) {
+            return *index;
+        }
+
+        let index = self.names.len() as i32;
+        self.names.push(name.clone());
+        self.indexes.insert(name.clone(), index);
+        index
+    }
+
+    fn entry(index: usize) -> String {
+        format!("000\\{index:08}")
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        (0..self.names.len())
+            .flat_map(|index| Self::entry(index).into_bytes())
+            .collect()
+    }
+}
+
+/// Action nodes in the li list: offset of the first option and count
+type LiOffsets = HashMap<String, (i32, i32)>;
+
+fn transition_fields(
+    offsets: &LiOffsets,
+    transition: &Option<Transition>,
+) -> Result<(i32, i32, i32)> {
+    let Some(transition) = transition else {
+        return Ok((-1, -1, -1));
+    };
+
+    let (offset, count) = offsets
+        .get(&transition.action_node)
+        .with_context(|| format!("Missing action node {}", transition.action_node))?;
+    Ok((*offset, *count, transition.option_index as i32))
+}
+
 impl Book {
+    #[allow(clippy::too_many_arguments)]
     fn create_transition(
+        namespace: &Uuid,
         li: &Li,
         stage_nodes: &Vec<StageNode>,
         action_nodes: &mut Vec<ActionNode>,
@@ -149,20 +230,27 @@ impl Book {
             return None;
         }
 
-        let id = Uuid::new_v4().to_string();
-        let mut options = Vec::new();
+        /* The same list of options is always the same action node, then the
+         * ids are stable between two loads (and the positions can be restored).
+         */
+        let name = format!("action/{index}/{count}");
+        let id = Uuid::new_v5(namespace, name.as_bytes()).to_string();
 
-        for index in index..(index + count) {
-            let stage_node_index = li.list[index as usize];
-            options.push(stage_nodes[stage_node_index as usize].uuid.clone());
-        }
+        if !actions.contains_key(&id) {
+            let mut options = Vec::new();
 
-        let action = ActionNode {
-            id: id.clone(),
-            options,
-        };
-        action_nodes.push(action);
-        actions.insert(id.clone(), action_nodes.len() - 1);
+            for index in index..(index + count) {
+                let stage_node_index = li.list[index as usize];
+                options.push(stage_nodes[stage_node_index as usize].uuid.clone());
+            }
+
+            let action = ActionNode {
+                id: id.clone(),
+                options,
+            };
+            action_nodes.push(action);
+            actions.insert(id.clone(), action_nodes.len() - 1);
+        }
 
         Some(Transition {
             action_node: id,
@@ -170,20 +258,35 @@ impl Book {
         })
     }
 
-    fn gen_thumbnail(path: &Path, image: &String) -> Result<()> {
+    fn gen_thumbnail(path: &Path, image: &String, cipher: &Cipher) -> Result<()> {
         let thumbnail = path.join("thumbnail.png");
         if fs::exists(&thumbnail)? {
             return Ok(());
         }
 
         let rf_image = path.join("rf").join(image);
-        let image = FileReader::Encrypted(DecryptedFile::open(rf_image)?);
+        let image = FileReader::Encrypted(DecryptedFile::open_with(rf_image, cipher)?);
         let reader = BufReader::new(image);
         let img = image::load(reader, image::ImageFormat::Bmp)?;
         img.save_with_format(thumbnail, image::ImageFormat::Png)?;
         Ok(())
     }
 
+    /// Metadata of the pack (`key: value` lines), the md file is optional
+    fn md_read(path: &Path) -> HashMap<String, String> {
+        let Ok(md) = fs::read_to_string(path.join("md")) else {
+            return HashMap::new();
+        };
+
+        md.lines()
+            .filter_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                let value = value.trim().trim_matches('"');
+                (!value.is_empty()).then(|| (key.trim().to_string(), value.to_string()))
+            })
+            .collect()
+    }
+
     pub fn is_story_fs(path: &Path) -> bool {
         let story_li = path.join("li");
         let story_ni = path.join("ni");
@@ -195,11 +298,59 @@ impl Book {
             && story_si.try_exists().unwrap_or_default()
     }
 
-    pub(super) fn from_fs_directory(path: &Path) -> Result<Self> {
+    /// The story key (AES) is decrypted from the bt file with the device key
+    fn story_key(path: &Path, device_key: &Cipher) -> Result<Cipher> {
+        let bt = fs::read(path.join("bt"))?;
+        let bt = bt.get(..32).context("The bt file is too short")?;
+        let bytes = device_key.decrypt_block(bt);
+        Ok(Cipher::from_aes_bytes(bytes.as_slice().try_into()?))
+    }
+
+    fn pack_cipher(
+        path: &Path,
+        pack_version: PackVersion,
+        device_key: Option<&Cipher>,
+    ) -> Result<Cipher> {
+        match pack_version {
+            PackVersion::V1 => Ok(Cipher::GENERIC),
+            /* The generic key if the device key doesn't decrypt the pack */
+            PackVersion::V2 => Ok(match device_key {
+                Some(key @ Cipher::Xxtea(_))
+                    if Ri::from_file(&path.join("ri"), key)
+                        .is_ok_and(|ri| names_valid(&ri.list)) =>
+                {
+                    *key
+                }
+                _ => Cipher::GENERIC,
+            }),
+            PackVersion::V3 => {
+                let device_key =
+                    device_key.context("Lunii v3 story pack, the device key is required")?;
+                Self::story_key(path, device_key)
+            }
+        }
+    }
+
+    /// Cipher of the assets of a story FS (without to read the whole pack)
+    pub(super) fn fs_cipher(path: &Path, device_key: Option<&Cipher>) -> Result<Cipher> {
         let ni = Ni::from_file(&path.join("ni"))?;
-        let li = Li::from_file(&path.join("li"))?;
-        let ri = Ri::from_file(&path.join("ri"))?;
-        let si = Si::from_file(&path.join("si"))?;
+        Self::pack_cipher(path, ni.version(path)?, device_key)
+    }
+
+    pub(super) fn from_fs_directory(path: &Path, device_key: Option<&Cipher>) -> Result<Self> {
+        let ni = Ni::from_file(&path.join("ni"))?;
+        let pack_version = ni.version(path)?;
+        let cipher = Self::pack_cipher(path, pack_version, device_key)?;
+
+        let li = Li::from_file(&path.join("li"), &cipher)?;
+        let ri = Ri::from_file(&path.join("ri"), &cipher)?;
+        let si = Si::from_file(&path.join("si"), &cipher)?;
+
+        /* With a wrong key, the assets names are garbage */
+        if pack_version != PackVersion::V1 && !(names_valid(&ri.list) && names_valid(&si.list)) {
+            bail!("Cannot decrypt the story pack, wrong device key");
+        }
+
         let nm = path.join("nm");
 
         let format = format!("v{}", ni.header.format_version);
@@ -210,15 +361,17 @@ impl Book {
         let mut stages = HashMap::new();
         let mut actions = HashMap::new();
 
-        let mut uuid = path
+        let id = path
             .file_name()
             .context("Missing folder name")?
             .to_string_lossy()
             .to_string();
+        let namespace = Uuid::new_v5(&Uuid::NAMESPACE_OID, id.as_bytes());
+        let mut uuid = id.clone();
         let mut square_one = true;
         let start_node_uuid = Some(uuid.clone());
 
-        for node in &ni.nodes {
+        for (i, node) in ni.nodes.iter().enumerate() {
             let image = ri.list.get(node.image_asset_index as usize).map(|bytes| {
                 String::from_utf8_lossy(bytes)
                     .to_string()
@@ -248,10 +401,11 @@ impl Book {
                  * the title is not available.
                  */
                 if let Some(ref image) = image {
-                    Self::gen_thumbnail(path, image)?;
+                    Self::gen_thumbnail(path, image, &cipher)?;
                 }
             } else {
-                uuid = Uuid::new_v4().to_string();
+                let name = format!("stage/{i}");
+                uuid = Uuid::new_v5(&namespace, name.as_bytes()).to_string();
             }
 
             let stage = StageNode {
@@ -273,6 +427,7 @@ impl Book {
             let node = ni.nodes[i];
 
             let ok_transition = Self::create_transition(
+                &namespace,
                 &li,
                 &stage_nodes,
                 &mut action_nodes,
@@ -282,6 +437,7 @@ impl Book {
                 node.ok_transition_selected_option,
             );
             let home_transition = Self::create_transition(
+                &namespace,
                 &li,
                 &stage_nodes,
                 &mut action_nodes,
@@ -296,9 +452,15 @@ impl Book {
             stage_node.home_transition = home_transition;
         }
 
+        /* Without metadata, the folder name is the end of the pack uuid */
+        let mut md = Self::md_read(path);
+        let pack_uuid = md.remove("uuid").or_else(|| Some(id.clone()));
+
         let story = Story {
             format,
             version,
+            title: md.remove("title"),
+            description: md.remove("description"),
             night_mode_available,
             stage_nodes,
             action_nodes,
@@ -309,7 +471,13 @@ impl Book {
         let current_action_node = None;
 
         Ok(Self {
-            encrypted: true,
+            id,
+            uuid: pack_uuid,
+            path: path.to_path_buf(),
+            cipher: Some(cipher),
+            zip: None,
+            zip_index: OnceLock::new(),
+            gains: OnceLock::new(),
             images_path: path.join("rf").to_path_buf(),
             audio_path: path.join("sf").to_path_buf(),
             story,
@@ -319,13 +487,161 @@ impl Book {
             current_stage_node,
             current_action_node,
             current_action_index,
+            history: VecDeque::new(),
         })
     }
+
+    fn fs_image(&self, image: &String) -> Result<Vec<u8>> {
+        let (file, _) = self.images_file_get(image)?;
+        let reader = image::ImageReader::new(BufReader::new(file)).with_guessed_format()?;
+        if reader.format() == Some(image::ImageFormat::Bmp) {
+            let mut bytes = Vec::new();
+            reader.into_inner().read_to_end(&mut bytes)?;
+            return Ok(Cipher::GENERIC.encrypt_block(&bytes));
+        }
+
+        /* The story FS supports only the BMP images */
+        let img = reader.decode()?;
+        let mut bytes = Vec::new();
+        img.write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Bmp)?;
+        Ok(Cipher::GENERIC.encrypt_block(&bytes))
+    }
+
+    /// Convert a book to a Lunii story FS (v1, XXTEA). The cover stage is
+    /// always the first node. The audio files are copied as is.
+    pub fn to_fs_directory(&self, dest: &Path) -> Result<()> {
+        if fs::exists(dest)? {
+            bail!("{dest:?} already exists");
+        }
+
+        let start = self
+            .start_node_uuid
+            .as_ref()
+            .and_then(|uuid| self.stages.get(uuid))
+            .context("Missing square one stage node")?;
+
+        /* The reader expects the cover in first */
+        let mut order: Vec<usize> = vec![*start];
+        order.extend((0..self.story.stage_nodes.len()).filter(|i| i != start));
+        let mut indexes = HashMap::new();
+        for (index, i) in order.iter().enumerate() {
+            indexes.insert(self.story.stage_nodes[*i].uuid.as_str(), index as u32);
+        }
+
+        /* Only the action nodes used by a transition are written */
+        let mut li = Vec::new();
+        let mut offsets = LiOffsets::new();
+        for stage_node in &self.story.stage_nodes {
+            for transition in [&stage_node.ok_transition, &stage_node.home_transition]
+                .into_iter()
+                .flatten()
+            {
+                let id = &transition.action_node;
+                if offsets.contains_key(id) {
+                    continue;
+                }
+
+                let action_node = self
+                    .actions
+                    .get(id)
+                    .map(|&i| &self.story.action_nodes[i])
+                    .with_context(|| format!("Missing action node {id}"))?;
+
+                let offset = li.len() as i32;
+                for uuid in &action_node.options {
+                    let index = indexes
+                        .get(uuid.as_str())
+                        .with_context(|| format!("Missing stage node {uuid}"))?;
+                    li.push(*index);
+                }
+                offsets.insert(id.clone(), (offset, action_node.options.len() as i32));
+            }
+        }
+
+        let mut ri = Assets::default();
+        let mut si = Assets::default();
+        let mut nodes = Vec::new();
+        for i in &order {
+            let stage_node = &self.story.stage_nodes[*i];
+            let ok = transition_fields(&offsets, &stage_node.ok_transition)?;
+            let home = transition_fields(&offsets, &stage_node.home_transition)?;
+            let control_settings = &stage_node.control_settings;
+
+            nodes.push(Node {
+                image_asset_index: ri.index(&stage_node.image),
+                sound_asset_index: si.index(&stage_node.audio),
+                ok_transition_action_index: ok.0,
+                ok_transition_options_count: ok.1,
+                ok_transition_selected_option: ok.2,
+                home_transition_action_index: home.0,
+                home_transition_options_count: home.1,
+                home_transition_selected_option: home.2,
+                control_wheel_enabled: control_settings.wheel as u16,
+                control_ok_enabled: control_settings.ok as u16,
+                control_home_enabled: control_settings.home as u16,
+                control_pause_enabled: control_settings.pause as u16,
+                control_autoplay_enabled: control_settings.autoplay as u16,
+                padding: 0,
+            });
+        }
+
+        let header = NiHeader {
+            format_version: 1,
+            story_version: self.story.version as u16,
+            nodes_list_offset: size_of::<NiHeader>() as u32,
+            node_size: size_of::<Node>() as u32,
+            stage_nodes_count: nodes.len() as u32,
+            image_assets_count: ri.names.len() as u32,
+            sound_assets_count: si.names.len() as u32,
+            factory_disabled: 0,
+            padding: [0; 487],
+        };
+
+        let rf = dest.join("rf").join("000");
+        let sf = dest.join("sf").join("000");
+        fs::create_dir_all(&rf)?;
+        fs::create_dir_all(&sf)?;
+
+        for (index, image) in ri.names.iter().enumerate() {
+            fs::write(rf.join(format!("{index:08}")), self.fs_image(image)?)?;
+        }
+        for (index, audio) in si.names.iter().enumerate() {
+            let mut bytes = Vec::new();
+            self.audio_file_get(audio)?.read_to_end(&mut bytes)?;
+            fs::write(
+                sf.join(format!("{index:08}")),
+                Cipher::GENERIC.encrypt_block(&bytes),
+            )?;
+        }
+
+        let mut ni = bytemuck::bytes_of(&header).to_vec();
+        ni.extend_from_slice(bytemuck::cast_slice(&nodes));
+        fs::write(dest.join("ni"), ni)?;
+        fs::write(
+            dest.join("li"),
+            Cipher::GENERIC.encrypt_block(bytemuck::cast_slice(&li)),
+        )?;
+        fs::write(
+            dest.join("ri"),
+            Cipher::GENERIC.encrypt_block(&ri.to_bytes()),
+        )?;
+        fs::write(
+            dest.join("si"),
+            Cipher::GENERIC.encrypt_block(&si.to_bytes()),
+        )?;
+        if self.story.night_mode_available {
+            fs::write(dest.join("nm"), [])?;
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::Key;
+    use crate::book::story_archive::tests::story_write;
 
     #[test]
     fn load_ni() {
@@ -347,9 +663,10 @@ mod tests {
 
     #[test]
     fn load_li() {
-        let li = Li::from_file(Path::new(
-            "/home/schroeterm/devel/lunii/nathan/.content/2643948D/li",
-        ))
+        let li = Li::from_file(
+            Path::new("/home/schroeterm/devel/lunii/nathan/.content/2643948D/li"),
+            &Cipher::GENERIC,
+        )
         .expect("cannot read ni file");
 
         assert_eq!(li.list.len(), 15);
@@ -357,9 +674,10 @@ mod tests {
 
     #[test]
     fn load_ri() {
-        let ri = Ri::from_file(Path::new(
-            "/home/schroeterm/devel/lunii/nathan/.content/2643948D/ri",
-        ))
+        let ri = Ri::from_file(
+            Path::new("/home/schroeterm/devel/lunii/nathan/.content/2643948D/ri"),
+            &Cipher::GENERIC,
+        )
         .expect("cannot read ri file");
 
         for r in ri.list.iter().enumerate() {
@@ -371,9 +689,10 @@ mod tests {
 
     #[test]
     fn load_si() {
-        let si = Si::from_file(Path::new(
-            "/home/schroeterm/devel/lunii/nathan/.content/2643948D/si",
-        ))
+        let si = Si::from_file(
+            Path::new("/home/schroeterm/devel/lunii/nathan/.content/2643948D/si"),
+            &Cipher::GENERIC,
+        )
         .expect("cannot read si file");
 
         for r in si.list.iter().enumerate() {
@@ -386,6 +705,220 @@ mod tests {
     #[test]
     fn load_story_fs() {
         let pk = Path::new("/home/schroeterm/devel/lunii/nathan/.content/2643948D");
-        let book = Book::from_fs_directory(pk).expect("story fs not found");
+        let book = Book::from_fs_directory(pk, None).expect("story fs not found");
+    }
+
+    #[test]
+    fn write_story_fs() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let src = dir.path().join("src");
+        let dest = dir.path().join("2643948D");
+        story_write(&src, |_| {});
+
+        let book = Book::from_archive_file(&src).expect("story.json not found");
+        book.to_fs_directory(&dest).expect("cannot write story fs");
+        assert!(book.to_fs_directory(&dest).is_err());
+
+        let ni = Ni::from_file(&dest.join("ni")).expect("cannot read ni file");
+        assert_eq!(ni.header.format_version, 1);
+        assert_eq!(ni.header.nodes_list_offset, 0x200);
+        assert_eq!(ni.header.node_size, 0x2C);
+        assert_eq!(ni.header.stage_nodes_count, 17);
+        let ri = Ri::from_file(&dest.join("ri"), &Cipher::GENERIC).expect("cannot read ri file");
+        assert_eq!(ri.list.len(), ni.header.image_assets_count as usize);
+        assert_eq!(&ri.list[1], b"000\\00000001");
+        let li = Li::from_file(&dest.join("li"), &Cipher::GENERIC).expect("cannot read li file");
+        assert_eq!(li.list.len(), 15);
+
+        let fs_book = Book::from_fs_directory(&dest, None).expect("story fs not found");
+        assert_eq!(
+            fs_book.story.stage_nodes.len(),
+            book.story.stage_nodes.len()
+        );
+        assert_eq!(
+            fs_book.story.action_nodes.len(),
+            book.story.action_nodes.len()
+        );
+
+        /* The cover is moved in first, the other stages keep their order */
+        let start = book.stages[book.start_node_uuid.as_ref().unwrap()];
+        let mut order = vec![start];
+        order.extend((0..book.story.stage_nodes.len()).filter(|i| *i != start));
+        let uuids: HashMap<&str, &str> = order
+            .iter()
+            .zip(fs_book.story.stage_nodes.iter())
+            .map(|(i, fs_stage)| {
+                let stage = &book.story.stage_nodes[*i];
+                (stage.uuid.as_str(), fs_stage.uuid.as_str())
+            })
+            .collect();
+
+        let options = |book: &Book, transition: &Option<Transition>| {
+            transition.as_ref().map(|transition| {
+                let action = book.actions[&transition.action_node];
+                let options = book.story.action_nodes[action].options.clone();
+                (options, transition.option_index)
+            })
+        };
+
+        for stage in &book.story.stage_nodes {
+            let fs_stage = &fs_book.story.stage_nodes[fs_book.stages[uuids[stage.uuid.as_str()]]];
+            assert_eq!(fs_stage.control_settings, stage.control_settings);
+            assert_eq!(fs_stage.image.is_some(), stage.image.is_some());
+
+            for (transition, fs_transition) in [
+                (&stage.ok_transition, &fs_stage.ok_transition),
+                (&stage.home_transition, &fs_stage.home_transition),
+            ] {
+                let expected = options(&book, transition).map(|(options, index)| {
+                    let options: Vec<String> = options
+                        .iter()
+                        .map(|uuid| uuids[uuid.as_str()].to_string())
+                        .collect();
+                    (options, index)
+                });
+                assert_eq!(options(&fs_book, fs_transition), expected);
+            }
+
+            /* The assets are decrypted by the reader */
+            if let Some(ref audio) = stage.audio {
+                let fs_audio = fs_stage.audio.as_ref().unwrap();
+                let mut file = fs_book.audio_file_get(fs_audio).expect("audio not found");
+                let mut content = String::new();
+                file.read_to_string(&mut content)
+                    .expect("cannot read audio");
+                assert_eq!(&content, audio);
+            }
+            if let Some(ref image) = fs_stage.image {
+                let (file, format) = fs_book.images_file_get(image).expect("image not found");
+                let img = image::load(BufReader::new(file), format).expect("invalid image");
+                assert_eq!((img.width(), img.height()), (4, 3));
+            }
+        }
+
+        assert!(fs::exists(dest.join("thumbnail.png")).unwrap());
+
+        /* Without the md file, the uuid is the folder name */
+        let info = fs_book.info();
+        assert_eq!(info.uuid.as_deref(), Some("2643948D"));
+        assert_eq!(info.thumbnail, Some(dest.join("thumbnail.png")));
+        assert_eq!(info.title, None);
+
+        let uuid = "2f0f3109-bfae-4e09-91d7-ca0c2643948d";
+        fs::write(
+            dest.join("md"),
+            format!("uuid: {uuid}\ntitle: \"Title\"\ndescription:\n"),
+        )
+        .unwrap();
+        let info = Book::from_fs_directory(&dest, None)
+            .expect("story fs not found")
+            .info();
+        assert_eq!(info.uuid.as_deref(), Some(uuid));
+        assert_eq!(info.title.as_deref(), Some("Title"));
+        assert_eq!(info.description, None);
+    }
+
+    #[test]
+    fn read_story_fs_v2() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let src = dir.path().join("src");
+        let dest = dir.path().join("2643948D");
+        story_write(&src, |_| {});
+
+        let book = Book::from_archive_file(&src).expect("story.json not found");
+        book.to_fs_directory(&dest).expect("cannot write story fs");
+
+        /* Convert the v1 pack to a v2 pack encrypted with a device key */
+        let device_key = Cipher::Xxtea(Key::from_bytes(&[7; 16]));
+        let mut files = vec![dest.join("li"), dest.join("ri"), dest.join("si")];
+        for assets in ["rf", "sf"] {
+            for entry in fs::read_dir(dest.join(assets).join("000")).unwrap() {
+                files.push(entry.unwrap().path());
+            }
+        }
+        for file in files {
+            let bytes = Cipher::GENERIC.decrypt_block(&fs::read(&file).unwrap());
+            fs::write(&file, device_key.encrypt_block(&bytes)).unwrap();
+        }
+        fs::write(dest.join("bt"), [0; 64]).unwrap();
+
+        let e = Book::from_fs_directory(&dest, None).expect_err("the key is required");
+        assert!(e.to_string().contains("wrong device key"));
+        let wrong_key = Cipher::Xxtea(Key::from_bytes(&[8; 16]));
+        assert!(Book::from_fs_directory(&dest, Some(&wrong_key)).is_err());
+
+        let fs_book =
+            Book::from_fs_directory(&dest, Some(&device_key)).expect("story fs not found");
+        assert_eq!(fs_book.cipher, Some(device_key));
+        assert_eq!(
+            Book::fs_cipher(&dest, Some(&device_key)).expect("no cipher"),
+            device_key
+        );
+
+        let stage = &fs_book.story.stage_nodes[0];
+        let (file, format) = fs_book
+            .images_file_get(stage.image.as_ref().unwrap())
+            .expect("image not found");
+        let img = image::load(BufReader::new(file), format).expect("invalid image");
+        assert_eq!((img.width(), img.height()), (4, 3));
+    }
+
+    #[test]
+    fn read_story_fs_v3() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let src = dir.path().join("src");
+        let dest = dir.path().join("2643948D");
+        story_write(&src, |_| {});
+
+        let book = Book::from_archive_file(&src).expect("story.json not found");
+        book.to_fs_directory(&dest).expect("cannot write story fs");
+
+        /* Convert the v1 pack to a v3 pack */
+        let story_key = Cipher::from_aes_bytes(&[1; 32]);
+        let device_key = Cipher::from_aes_bytes(&[2; 32]);
+        let mut files = vec![dest.join("li"), dest.join("ri"), dest.join("si")];
+        for assets in ["rf", "sf"] {
+            for entry in fs::read_dir(dest.join(assets).join("000")).unwrap() {
+                files.push(entry.unwrap().path());
+            }
+        }
+        for file in files {
+            let bytes = Cipher::GENERIC.decrypt_block(&fs::read(&file).unwrap());
+            fs::write(&file, story_key.encrypt_block(&bytes)).unwrap();
+        }
+        let mut ni = fs::read(dest.join("ni")).unwrap();
+        ni[0] = 3;
+        fs::write(dest.join("ni"), ni).unwrap();
+        fs::write(dest.join("bt"), device_key.encrypt_block(&[1; 32])).unwrap();
+
+        let e = Book::from_fs_directory(&dest, None).expect_err("the key is required");
+        assert!(e.to_string().contains("device key is required"));
+        let wrong_key = Cipher::from_aes_bytes(&[3; 32]);
+        assert!(Book::from_fs_directory(&dest, Some(&wrong_key)).is_err());
+
+        let fs_book =
+            Book::from_fs_directory(&dest, Some(&device_key)).expect("story fs not found");
+        assert_eq!(fs_book.story.format, "v3");
+        assert_eq!(
+            fs_book.story.stage_nodes.len(),
+            book.story.stage_nodes.len()
+        );
+
+        let stage = &fs_book.story.stage_nodes[0];
+        let (file, format) = fs_book
+            .images_file_get(stage.image.as_ref().unwrap())
+            .expect("image not found");
+        let img = image::load(BufReader::new(file), format).expect("invalid image");
+        assert_eq!((img.width(), img.height()), (4, 3));
+
+        let start = book.stages[book.start_node_uuid.as_ref().unwrap()];
+        let audio = book.story.stage_nodes[start].audio.as_ref().unwrap();
+        let mut file = fs_book
+            .audio_file_get(stage.audio.as_ref().unwrap())
+            .expect("audio not found");
+        let mut content = String::new();
+        file.read_to_string(&mut content)
+            .expect("cannot read audio");
+        assert_eq!(&content, audio);
     }
 }
@@ -0,0 +1,166 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::{Context, Result};
+use std::{collections::HashSet, fs::File, io::BufReader, path::Path};
+use zip::ZipArchive;
+
+use crate::archive::ZipIndex;
+
+use super::book::Book;
+use super::book::Story;
+use super::story_archive::STORY_JSON;
+
+impl Book {
+    pub fn is_story_zip(path: &Path) -> bool {
+        path.is_file()
+            && path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+    }
+
+    /// Open a STUdio story pack without extracting it
+    pub(super) fn from_zip_file(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let mut zip = ZipArchive::new(BufReader::new(file))?;
+
+        /* The story can be at the root or in a root folder */
+        let story_name = zip
+            .file_names()
+            .filter(|name| Path::new(name).file_name() == Some(STORY_JSON.as_ref()))
+            .filter(|name| name.matches('/').count() <= 1)
+            .min_by_key(|name| name.len())
+            .context("Missing story.json")?
+            .to_string();
+        let story: Story = serde_json::from_reader(zip.by_name(&story_name)?)?;
+
+        let id = path
+            .file_stem()
+            .context("Missing file name")?
+            .to_string_lossy()
+            .to_string();
+
+        let assets = Path::new(&story_name).with_file_name("assets");
+        let mut book = Self::from_story(id, path, &assets, story);
+        book.zip = Some(path.to_path_buf());
+        Ok(book)
+    }
+
+    /// Entries of the zip archive, the central directory is read once
+    pub(super) fn zip_index(&self) -> Result<&ZipIndex> {
+        if let Some(index) = self.zip_index.get() {
+            return Ok(index);
+        }
+        let index = ZipIndex::open(self.zip.as_ref().context("Not a zip archive")?)?;
+        Ok(self.zip_index.get_or_init(|| index))
+    }
+
+    /// Names of the entries when the book is a zip archive
+    pub(super) fn zip_entries(&self) -> Option<HashSet<String>> {
+        self.zip.as_ref()?;
+        let index = self.zip_index().ok()?;
+        Some(index.names().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::Source;
+    use crate::book::story_archive::tests::story_write;
+    use std::{fs, io::Read, io::Write};
+    use zip::{CompressionMethod, write::SimpleFileOptions};
+
+    /// Zip a STUdio folder, the MP3 files are stored like STUdio does
+    fn story_zip(src: &Path, dest: &Path, root: &str) {
+        let mut zip = zip::ZipWriter::new(File::create(dest).expect("cannot create zip"));
+        let mut paths = vec![src.join(STORY_JSON), src.join("thumbnail.png")];
+        for entry in fs::read_dir(src.join("assets")).expect("cannot read assets") {
+            paths.push(entry.expect("cannot read assets").path());
+        }
+
+        for path in paths {
+            let name = path.strip_prefix(src).unwrap().to_string_lossy();
+            let method = match path.extension() {
+                Some(ext) if ext == "mp3" => CompressionMethod::Stored,
+                _ => CompressionMethod::Deflated,
+            };
+            let options = SimpleFileOptions::default().compression_method(method);
+            zip.start_file(format!("{root}{name}"), options)
+                .expect("cannot start file");
+            zip.write_all(&fs::read(&path).expect("cannot read file"))
+                .expect("cannot write file");
+        }
+        zip.finish().expect("cannot finish zip");
+    }
+
+    #[test]
+    fn load_story_zip() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let src = dir.path().join("src");
+        story_write(&src, |_| {});
+        fs::write(src.join("thumbnail.png"), "thumbnail").unwrap();
+        let book = Book::from_archive_file(&src).expect("story.json not found");
+        let cache_dir = dir.path().join("cache");
+
+        for root in ["", "book/"] {
+            let path = dir.path().join(format!("{}book.zip", root.len()));
+            story_zip(&src, &path, root);
+            assert!(matches!(Source::detect(&path), Some(Source::StoryZip(_))));
+
+            let zip_book = Book::from_zip_file(&path).expect("story.json not found");
+            assert_eq!(zip_book.id(), format!("{}book", root.len()));
+            assert_eq!(zip_book.stages, book.stages);
+            assert_eq!(zip_book.check(), book.check());
+
+            /* The thumbnail is extracted in the cache */
+            assert_eq!(zip_book.info().thumbnail, None);
+            let thumbnail = cache_dir
+                .join(format!("{}book.zip", root.len()))
+                .join("thumbnail.png");
+            assert_eq!(
+                zip_book
+                    .thumbnail_cache(&cache_dir)
+                    .expect("cannot extract"),
+                Some(thumbnail.clone())
+            );
+            assert_eq!(fs::read(&thumbnail).unwrap(), b"thumbnail");
+
+            for stage_node in &book.story.stage_nodes {
+                if let Some(ref audio) = stage_node.audio {
+                    let mut file = zip_book.audio_file_get(audio).expect("audio not found");
+                    let mut content = String::new();
+                    file.read_to_string(&mut content)
+                        .expect("cannot read audio");
+                    assert_eq!(&content, audio);
+                }
+                if let Some(ref image) = stage_node.image {
+                    let (mut file, _) = zip_book.images_file_get(image).expect("image not found");
+                    let mut content = Vec::new();
+                    file.read_to_end(&mut content).expect("cannot read image");
+                    assert_eq!(content, fs::read(src.join("assets").join(image)).unwrap());
+                }
+            }
+        }
+
+        /* A broken archive is detected but not loaded */
+        let path = dir.path().join("broken.zip");
+        fs::write(&path, "not a zip").unwrap();
+        assert!(matches!(Source::detect(&path), Some(Source::StoryZip(_))));
+        assert!(Book::from_zip_file(&path).is_err());
+    }
+}
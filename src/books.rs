@@ -15,93 +15,809 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use crate::book::{Book, book::Source};
+use crate::book::{Book, BookInfo, Issue, Severity, Source};
+use crate::decrypt::Cipher;
+use crate::order::{Order, Ordering};
+use crate::playlist::Playlist;
+use crate::state::{Resume, State};
 use anyhow::Result;
+use rand::seq::IndexedRandom;
 use std::{
+    collections::HashSet,
     error::Error,
     fs,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
+const STATE_FILE: &str = ".contelia-state.json";
+const CACHE_DIR: &str = ".contelia-cache";
+
+/// A book of the library, the story is only parsed when the book is
+/// selected (or when its metadata are not in the cache).
+struct Entry {
+    path: PathBuf,
+    id: String,
+    info: Option<BookInfo>,
+    book: Option<Book>,
+    /// A playlist is a pseudo-book, its book is the one of the current item
+    playlist: Option<Playlist>,
+}
+
 pub struct Books {
     path: PathBuf,
-    books: Vec<Book>,
+    entries: Vec<Entry>,
     current_book_index: usize,
+    state: State,
+    resume: Resume,
+    order: Order,
+    ordering: Ordering,
+    device_key: Option<Cipher>,
+    audio_offset: Duration,
+    night_only: bool,
+    shuffle: bool,
+    played: Vec<String>,
+    volume: Option<usize>,
 }
 
 impl Books {
-    pub fn from_dir(path: &Path) -> Result<Self> {
+    pub fn from_dir(
+        path: &Path,
+        resume: Resume,
+        order: Order,
+        device_key: Option<Cipher>,
+    ) -> Result<Self> {
         let current_book_index = 0;
-        let books = Self::load(path).unwrap_or_default();
+        let entries = Self::load(path).unwrap_or_default();
+        let ordering = Ordering::load(path);
+        let state = State::load(&path.join(STATE_FILE)).unwrap_or_else(|e| {
+            eprintln!("Cannot load the state: {}", e);
+            State::default()
+        });
 
-        Ok(Self {
+        let mut books = Self {
             path: path.to_path_buf(),
-            books,
+            entries,
             current_book_index,
-        })
+            state,
+            resume,
+            order,
+            ordering,
+            device_key,
+            audio_offset: Duration::ZERO,
+            night_only: false,
+            shuffle: false,
+            played: Vec::new(),
+            volume: None,
+        };
+        books.sort();
+        books.restore();
+
+        Ok(books)
     }
 
-    fn load(path: &Path) -> Result<Vec<Book>, Box<dyn Error>> {
-        let mut books = Vec::new();
+    fn load(path: &Path) -> Result<Vec<Entry>, Box<dyn Error>> {
+        let mut entries = Vec::new();
+        let cache_dir = path.join(CACHE_DIR);
 
         for entry in fs::read_dir(&path)? {
             let entry = entry?;
-            let path = entry.path();
-            if !path.is_dir() {
-                continue;
+            if let Some(entry) = Self::load_entry(&entry.path(), &cache_dir)? {
+                entries.push(entry);
             }
+        }
+
+        println!("Found {} books", entries.len());
+
+        Ok(entries)
+    }
+
+    /// Find a book of the books directory, the disabled books are skipped.
+    /// Only the cached metadata are loaded.
+    fn load_entry(path: &Path, cache_dir: &Path) -> Result<Option<Entry>> {
+        if Playlist::is_playlist(path) {
+            let playlist = match Playlist::load(path) {
+                Ok(playlist) => playlist,
+                Err(e) => {
+                    eprintln!("Cannot load the playlist {:?}: {}", path, e);
+                    return Ok(None);
+                }
+            };
+            let id = path.file_name().unwrap_or_default().to_string_lossy();
+            return Ok(Some(Entry {
+                path: path.to_path_buf(),
+                id: id.to_string(),
+                info: Some(playlist.info(&id)),
+                book: None,
+                playlist: Some(playlist),
+            }));
+        }
+
+        if !path.is_dir() && !Book::is_story_zip(path) {
+            return Ok(None);
+        }
+
+        let factory_disabled = path.join(".factory_disabled");
+        if fs::exists(&factory_disabled)? {
+            return Ok(None);
+        }
+
+        let Some(source) = Source::detect(path) else {
+            return Ok(None);
+        };
+
+        Ok(Some(Entry {
+            path: path.to_path_buf(),
+            id: source.id(),
+            info: Book::info_cached(&source, cache_dir),
+            book: None,
+            playlist: None,
+        }))
+    }
+
+    /// Load a book from the cache or parse it, the broken books are skipped.
+    fn load_book(path: &Path, device_key: Option<&Cipher>, cache_dir: &Path) -> Option<Book> {
+        let source = Source::detect(path)?;
+        if let Some(book) = Book::from_cache(&source, device_key, cache_dir) {
+            return Some(book);
+        }
 
-            let factory_disabled = path.join(".factory_disabled");
-            if fs::exists(&factory_disabled)? {
+        match Book::from_source(source, device_key) {
+            Ok(book) => {
+                /* Broken books are skipped, otherwise they fail at runtime */
+                let issues = book.check();
+                Self::report(path, &issues);
+                if issues
+                    .iter()
+                    .all(|issue| issue.severity() < Severity::Error)
+                {
+                    if let Err(e) = book.cache_write(cache_dir) {
+                        eprintln!("Cannot cache the book {:?}: {}", path, e);
+                    }
+                    return Some(book);
+                }
+            }
+            Err(e) => eprintln!("Cannot load the book {:?}: {}", path, e),
+        }
+
+        None
+    }
+
+    /// Measure the loudness of the books not analyzed yet, it returns the
+    /// number of analyzed books. It's slow, it can run in a thread.
+    pub fn normalize(path: &Path, device_key: Option<&Cipher>) -> Result<usize> {
+        let cache_dir = path.join(CACHE_DIR);
+        let mut count = 0;
+
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            let Some(source) = Source::detect(&path) else {
                 continue;
+            };
+            if Book::gains_cached(&source, &cache_dir).is_some() {
+                continue;
+            }
+            let Some(book) = Self::load_book(&path, device_key, &cache_dir) else {
+                continue;
+            };
+
+            book.gains_write(&cache_dir, book.loudness_gains())?;
+            println!("Loudness analyzed: {:?}", path);
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Cache of the parsed books and of the loudness gains
+    pub fn cache_dir(&self) -> PathBuf {
+        self.path.join(CACHE_DIR)
+    }
+
+    /// Cache of the books next to this one
+    pub fn cache_dir_of(book: &Path) -> PathBuf {
+        book.parent().unwrap_or(Path::new("")).join(CACHE_DIR)
+    }
+
+    /// Parse the book of an entry (if not already done), the entry is
+    /// removed if the book cannot be loaded.
+    fn parse(&mut self, index: usize) -> bool {
+        let entry = &self.entries[index];
+        if entry.book.is_some() {
+            return true;
+        }
+
+        if entry.playlist.is_some() {
+            let Some(book) = self.playlist_load(index) else {
+                self.remove(index);
+                return false;
+            };
+            self.entries[index].book = Some(book);
+            return true;
+        }
+
+        let cache_dir = self.path.join(CACHE_DIR);
+        let Some(mut book) = Self::load_book(&entry.path, self.device_key.as_ref(), &cache_dir)
+        else {
+            self.remove(index);
+            return false;
+        };
+
+        self.position_restore(&mut book);
+        let entry = &mut self.entries[index];
+        entry.info = Some(book.info());
+        entry.book = Some(book);
+        true
+    }
+
+    /// Load the book of the current item of a playlist (the book of the
+    /// first item is used for the cover). The broken items are skipped.
+    fn playlist_load(&mut self, index: usize) -> Option<Book> {
+        let cache_dir = self.path.join(CACHE_DIR);
+
+        loop {
+            let playlist = self.entries[index].playlist.as_ref()?;
+            let started = playlist.current().is_some();
+            let item = playlist.current().or(playlist.items().first())?.clone();
+
+            let mut book = self
+                .entries
+                .iter()
+                .find(|entry| entry.id == item.book && entry.playlist.is_none())
+                .and_then(|entry| {
+                    Self::load_book(&entry.path, self.device_key.as_ref(), &cache_dir)
+                });
+            if started
+                && let Some(book) = &mut book
+                && item.select(book).is_none()
+            {
+                eprintln!(
+                    "Story not found in the book {}: {:?}",
+                    item.book, item.target
+                );
+            } else if book.is_some() || !started {
+                return book;
+            }
+
+            let playlist = self.entries[index].playlist.as_mut()?;
+            if playlist.advance().is_none() {
+                playlist.reset();
             }
+        }
+    }
+
+    /// True if the selected book is a playlist
+    pub fn is_playlist(&self) -> bool {
+        self.entries
+            .get(self.current_book_index)
+            .is_some_and(|entry| entry.playlist.is_some())
+    }
+
+    /// Start the selected playlist or play its next item. It returns None
+    /// after the last item, the playlist is back on its cover.
+    pub fn playlist_next(&mut self) -> Option<()> {
+        let index = self.current_book_index;
+        let playlist = self.entries.get_mut(index)?.playlist.as_mut()?;
+        let next = playlist.advance().map(|_| ());
+        if next.is_none() {
+            playlist.reset();
+        }
+
+        self.entries[index].book = None;
+        self.parse(index);
+        next
+    }
+
+    /// Back to the cover of the selected playlist
+    pub fn playlist_reset(&mut self) {
+        let index = self.current_book_index;
+        let Some(playlist) = self
+            .entries
+            .get_mut(index)
+            .and_then(|entry| entry.playlist.as_mut())
+        else {
+            return;
+        };
+        playlist.reset();
+
+        self.entries[index].book = None;
+        self.parse(index);
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.entries.remove(index);
+        if self.current_book_index > index {
+            self.current_book_index -= 1;
+        }
+        self.current_book_index = self
+            .current_book_index
+            .min(self.entries.len().saturating_sub(1));
+    }
+
+    /// Metadata of all books, the books without cached metadata are parsed
+    fn infos_load(&mut self) {
+        let mut index = 0;
+        while index < self.entries.len() {
+            if self.entries[index].info.is_some() || self.parse(index) {
+                index += 1;
+            }
+        }
+    }
+
+    fn sort(&mut self) {
+        if self.order == Order::Title {
+            self.infos_load();
+        }
+        self.ordering.sort(&mut self.entries, self.order, |entry| {
+            (
+                entry.id.as_str(),
+                entry.path.as_path(),
+                entry.info.as_ref().and_then(|info| info.title.as_deref()),
+            )
+        });
+    }
+
+    /// Print the issues found in a book
+    pub fn report(path: &Path, issues: &[Issue]) {
+        for issue in issues {
+            let severity = match issue.severity() {
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            };
+            eprintln!("{:?}: {}: {}", path, severity, issue);
+        }
+    }
+
+    /// Restore the position of a book from the state
+    fn position_restore(&self, book: &mut Book) {
+        if self.resume == Resume::None {
+            return;
+        }
+
+        let Some(position) = self.state.positions.get(book.id()) else {
+            return;
+        };
+        if book.position_restore(position).is_none() {
+            return;
+        }
+        if self.resume == Resume::Stories && !book.stage_get().is_some_and(|stage| stage.is_story())
+        {
+            book.stage_reset();
+        }
+    }
+
+    /// Restore the selected book from the state, the positions are restored
+    /// when the books are parsed.
+    fn restore(&mut self) {
+        self.current_book_index = 0;
+        self.audio_offset = Duration::ZERO;
+        self.played = self.state.played.clone();
+        self.volume = self.state.volume;
+
+        if self.resume == Resume::None {
+            return;
+        }
+
+        if let Some(id) = &self.state.book
+            && let Some(index) = self.entries.iter().position(|entry| &entry.id == id)
+        {
+            self.current_book_index = index;
+            self.resume_audio();
+        }
+    }
 
-            let source = if Book::is_story_archive(&path) {
-                Source::StoryArchive(&path)
-            } else if Book::is_story_fs(&path) {
-                Source::StoryFs(&path)
-            } else {
+    /// Continue the audio of the selected book from the saved offset
+    pub fn resume_audio(&mut self) {
+        /* The playlists always start on their cover */
+        if self.is_playlist() {
+            return;
+        }
+        let Some(book) = self.get() else {
+            return;
+        };
+        let id = book.id().to_string();
+        let stage_node = book.position().stage_node;
+
+        /* The offset is only useful if the position is still the same */
+        if let Some(position) = self.state.positions.get(&id)
+            && position.stage_node == stage_node
+        {
+            self.audio_offset = position.audio_offset;
+        }
+    }
+
+    /// Save the selected book and the positions (only if something has changed)
+    ///
+    /// The audio offset is the elapsed time in the current stage of the
+    /// selected book. The positions of the books not parsed are unchanged.
+    pub fn save(&mut self, audio_offset: Duration) -> Result<()> {
+        let mut state = self.state.clone();
+        state.book = self
+            .entries
+            .get(self.current_book_index)
+            .map(|entry| entry.id.clone());
+        state.played = self.played.clone();
+        state.volume = self.volume;
+        for (i, entry) in self.entries.iter().enumerate() {
+            let Some(book) = &entry.book else {
                 continue;
             };
+            if entry.playlist.is_some() {
+                continue;
+            }
+            let mut position = book.position();
+            if i == self.current_book_index {
+                position.audio_offset = audio_offset;
+            }
+            state.positions.insert(book.id().to_string(), position);
+        }
+
+        if state == self.state {
+            return Ok(());
+        }
+
+        state.save(&self.path.join(STATE_FILE))?;
+        self.state = state;
+        Ok(())
+    }
+
+    /// Last saved volume level
+    pub fn volume(&self) -> Option<usize> {
+        self.volume
+    }
+
+    /// The volume level is saved with the state
+    pub fn set_volume(&mut self, level: usize) {
+        self.volume = Some(level);
+    }
+
+    /// Audio offset of the restored position (only once)
+    pub fn take_audio_offset(&mut self) -> Duration {
+        std::mem::take(&mut self.audio_offset)
+    }
+
+    /// Reload the books and restore the last saved state
+    pub fn reload(&mut self) {
+        self.entries = Self::load(&self.path).unwrap_or_default();
+        self.ordering = Ordering::load(&self.path);
+        self.sort();
+        self.restore();
+    }
+
+    /// Reload only the changed entries of the books directory (see the
+    /// watcher). The positions are kept and the selected book stays selected
+    /// if it still exists.
+    pub fn refresh(&mut self, names: &HashSet<String>) {
+        let current = self
+            .entries
+            .get(self.current_book_index)
+            .map(|entry| entry.id.clone());
+        let cache_dir = self.path.join(CACHE_DIR);
+
+        for name in names {
+            let path = self.path.join(name);
+            let old = self
+                .entries
+                .iter()
+                .position(|entry| entry.path == path)
+                .map(|index| self.entries.remove(index));
 
-            match Book::from_source(source) {
-                Ok(book) => books.push(book),
+            match Self::load_entry(&path, &cache_dir) {
+                Ok(Some(entry)) => {
+                    self.entries.push(entry);
+
+                    /* A parsed book is parsed again in order to keep its position */
+                    let index = self.entries.len() - 1;
+                    if let Some(old) = old.and_then(|old| old.book)
+                        && self.entries[index].playlist.is_none()
+                        && self.parse(index)
+                        && let Some(book) = &mut self.entries[index].book
+                    {
+                        book.position_restore(&old.position());
+                    }
+                }
+                Ok(None) => (),
                 Err(e) => eprintln!("Cannot load the book {:?}: {}", path, e),
             }
         }
 
-        println!("Loaded {} books", books.len());
+        /* The order files are in the same directory */
+        self.ordering = Ordering::load(&self.path);
+        self.sort();
 
-        Ok(books)
+        let last = self.entries.len().saturating_sub(1);
+        self.current_book_index = current
+            .and_then(|id| self.entries.iter().position(|entry| entry.id == id))
+            .unwrap_or(self.current_book_index.min(last));
     }
 
-    pub fn reload(&mut self) {
-        let books = Self::load(&self.path).unwrap_or_default();
-        self.books = books;
-        self.current_book_index = 0;
+    /// Add or remove the selected book from the favorites, it stays selected
+    pub fn toggle_favorite(&mut self) -> Result<bool> {
+        let Some(entry) = self.entries.get(self.current_book_index) else {
+            return Ok(false);
+        };
+        let id = entry.id.clone();
+
+        self.ordering.toggle_favorite(&id);
+        self.ordering.save_favorites(&self.path)?;
+        self.sort();
+        if let Some(index) = self.entries.iter().position(|entry| entry.id == id) {
+            self.current_book_index = index;
+        }
+        Ok(self
+            .ordering
+            .favorites
+            .iter()
+            .any(|favorite| favorite == &id))
     }
 
+    /// Metadata of the books
+    pub fn infos(&mut self) -> Vec<BookInfo> {
+        self.infos_load();
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.info.clone())
+            .collect()
+    }
+
+    /// Selected book, it's parsed on demand
     pub fn get(&mut self) -> Option<&mut Book> {
-        self.books.get_mut(self.current_book_index)
+        while self.current_book_index < self.entries.len() && !self.parse(self.current_book_index) {
+        }
+        self.entries.get_mut(self.current_book_index)?.book.as_mut()
+    }
+
+    /// Skip the books without night mode (if at least one book has it)
+    pub fn set_night_only(&mut self, night_only: bool) {
+        self.night_only = night_only;
+        if night_only {
+            self.infos_load();
+        }
+
+        /* Leave the current book only if nothing is playing */
+        let index = self.current_book_index;
+        if let Some(entry) = self.entries.get(index)
+            && !self.is_visible(index)
+            && entry
+                .book
+                .as_ref()
+                .is_none_or(|book| book.stage_get().is_some_and(|stage| stage.square_one))
+        {
+            self.button_wheel_right();
+        }
+    }
+
+    fn is_visible(&self, index: usize) -> bool {
+        let night_mode = |entry: &Entry| entry.info.as_ref().is_some_and(|info| info.night_mode);
+        !self.night_only
+            || self.entries[index].playlist.is_some()
+            || night_mode(&self.entries[index])
+            || !self.entries.iter().any(night_mode)
+    }
+
+    /// The wheel on the cover selects a random book
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        self.shuffle = shuffle;
+    }
+
+    /// Returns true if the wheel selects a random book now
+    pub fn toggle_shuffle(&mut self) -> bool {
+        self.shuffle = !self.shuffle;
+        self.shuffle
+    }
+
+    /// Visible books not drawn yet (the selected book and the playlists are
+    /// excluded)
+    fn book_candidates(&self) -> Vec<usize> {
+        (0..self.entries.len())
+            .filter(|&index| index != self.current_book_index && self.is_visible(index))
+            .filter(|&index| self.entries[index].playlist.is_none())
+            .filter(|&index| !self.played.contains(&self.entries[index].id))
+            .collect()
+    }
+
+    /// Select a random book, a book comes back only when all the other
+    /// books were drawn.
+    fn book_random(&mut self) {
+        let mut candidates = self.book_candidates();
+        if candidates.is_empty() {
+            self.played.clear();
+            candidates = self.book_candidates();
+        }
+
+        if let Some(&index) = candidates.choose(&mut rand::rng()) {
+            self.current_book_index = index;
+            self.played.push(self.entries[index].id.clone());
+        }
+    }
+
+    /// Select a random story of a random book ("surprise me")
+    pub fn surprise(&mut self) {
+        for _ in 0..self.entries.len() {
+            self.book_random();
+            let Some(book) = self.get() else {
+                return;
+            };
+            if book.random_story().is_some() {
+                return;
+            }
+            /* No story in this book, try another one */
+            book.stage_reset();
+        }
     }
 
     pub fn button_wheel_right(&mut self) {
+        if self.shuffle {
+            self.book_random();
+            return;
+        }
+        for _ in 0..self.entries.len() {
+            self.book_next();
+            if self.is_visible(self.current_book_index) {
+                break;
+            }
+        }
+    }
+
+    pub fn button_wheel_left(&mut self) {
+        if self.shuffle {
+            self.book_random();
+            return;
+        }
+        for _ in 0..self.entries.len() {
+            self.book_previous();
+            if self.is_visible(self.current_book_index) {
+                break;
+            }
+        }
+    }
+
+    fn book_next(&mut self) {
         let mut book_index = self.current_book_index as isize;
         book_index = book_index + 1;
-        if book_index >= self.books.len() as isize {
+        if book_index >= self.entries.len() as isize {
             self.current_book_index = 0;
         } else {
             self.current_book_index = book_index as usize;
         }
     }
 
-    pub fn button_wheel_left(&mut self) {
+    fn book_previous(&mut self) {
         let mut book_index = self.current_book_index as isize;
         book_index = book_index - 1;
         if book_index < 0 {
-            self.current_book_index = self.books.len() - 1;
+            self.current_book_index = self.entries.len() - 1;
         } else {
             self.current_book_index = book_index as usize;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::story_archive::tests::story_write;
+
+    fn ids(books: &Books) -> Vec<&str> {
+        books
+            .entries
+            .iter()
+            .map(|entry| entry.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn refresh() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        story_write(&dir.path().join("a"), |_| {});
+        story_write(&dir.path().join("b"), |_| {});
+
+        let mut books =
+            Books::from_dir(dir.path(), Resume::All, Order::Name, None).expect("cannot load books");
+        assert_eq!(ids(&books), ["a", "b"]);
+        /* The books are parsed on demand */
+        assert!(books.entries.iter().all(|entry| entry.book.is_none()));
+        books.button_wheel_right();
+        books.get().unwrap().button_ok().expect("OK button fail");
+        let position = books.get().unwrap().position();
+
+        /* The selected book and its position are kept */
+        story_write(&dir.path().join("c"), |_| {});
+        fs::write(dir.path().join("a").join(".factory_disabled"), "").unwrap();
+        let names = HashSet::from(["a".into(), "b".into(), "c".into()]);
+        books.refresh(&names);
+        assert_eq!(ids(&books), ["b", "c"]);
+        assert_eq!(books.get().unwrap().id(), "b");
+        assert_eq!(books.get().unwrap().position(), position);
+
+        fs::remove_dir_all(dir.path().join("c")).unwrap();
+        books.button_wheel_right();
+        books.refresh(&HashSet::from(["c".into()]));
+        assert_eq!(ids(&books), ["b"]);
+        assert_eq!(books.get().unwrap().id(), "b");
+    }
+
+    #[test]
+    fn playlist() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        story_write(&dir.path().join("a"), |_| {});
+        story_write(&dir.path().join("b"), |_| {});
+        fs::write(
+            dir.path().join("bedtime.playlist"),
+            "a 1/1\nmissing\nb 1dfea263\n",
+        )
+        .unwrap();
+
+        let mut books =
+            Books::from_dir(dir.path(), Resume::All, Order::Name, None).expect("cannot load books");
+        assert_eq!(ids(&books), ["a", "b", "bedtime.playlist"]);
+        books.button_wheel_left();
+        assert!(books.is_playlist());
+
+        /* The cover is the one of the first book */
+        let book = books.get().unwrap();
+        assert_eq!(book.id(), "a");
+        assert!(book.stage_get().unwrap().square_one);
+
+        /* The missing book is skipped */
+        books.playlist_next().expect("first item not found");
+        assert_eq!(books.get().unwrap().id(), "a");
+        assert!(books.get().unwrap().stage_get().unwrap().is_story());
+        books.playlist_next().expect("second item not found");
+        let book = books.get().unwrap();
+        assert_eq!(book.id(), "b");
+        assert!(book.position().stage_node.unwrap().starts_with("1dfea263"));
+
+        /* Back on the cover after the last item */
+        assert!(books.playlist_next().is_none());
+        assert!(books.get().unwrap().stage_get().unwrap().square_one);
+
+        books.save(Duration::ZERO).expect("cannot save");
+        assert_eq!(books.state.book.as_deref(), Some("bedtime.playlist"));
+        assert!(books.state.positions.is_empty());
+    }
+
+    #[test]
+    fn shuffle() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        for id in ["a", "b", "c", "d"] {
+            story_write(&dir.path().join(id), |_| {});
+        }
+
+        let mut books =
+            Books::from_dir(dir.path(), Resume::All, Order::Name, None).expect("cannot load books");
+        books.set_shuffle(true);
+
+        /* No repeat until all the books were drawn */
+        let mut drawn = HashSet::new();
+        for _ in 0..4 {
+            books.button_wheel_right();
+            drawn.insert(books.get().unwrap().id().to_string());
+        }
+        assert_eq!(drawn.len(), 4);
+        let last = books.get().unwrap().id().to_string();
+        books.button_wheel_left();
+        assert_ne!(books.get().unwrap().id(), last);
+        assert_eq!(books.played.len(), 1);
+
+        /* The drawn books are saved */
+        books.save(Duration::ZERO).expect("cannot save");
+        let played = books.played.clone();
+        let books =
+            Books::from_dir(dir.path(), Resume::All, Order::Name, None).expect("cannot load books");
+        assert_eq!(books.played, played);
+    }
+
+    #[test]
+    fn surprise() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        story_write(&dir.path().join("a"), |_| {});
+        story_write(&dir.path().join("b"), |_| {});
+
+        let mut books =
+            Books::from_dir(dir.path(), Resume::All, Order::Name, None).expect("cannot load books");
+        books.surprise();
+        let stage = books.get().unwrap().stage_get().expect("stage not found");
+        assert!(stage.is_story());
+    }
+}
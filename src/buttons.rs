@@ -18,15 +18,31 @@
 use anyhow::Result;
 use evdev::{Device, KeyCode};
 use nix::sys::epoll;
-use std::{error::Error, path::Path};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// A button held this long is a long press (see `long_code`), the wheel is
+/// repeated while it's held. The short press of these buttons is sent on
+/// release.
+const LONG_PRESS: Duration = Duration::from_millis(600);
+const LONG_PRESS_REPEAT: Duration = Duration::from_millis(400);
+/// The first button of a chord (like UP and DOWN) waits this long for the
+/// other one, then it's sent alone
+const CHORD: Duration = Duration::from_millis(150);
 
 pub struct Buttons {
     device: Device,
     epoll: epoll::Epoll,
+    gestures: Gestures,
+    /// Status of the last sent button
     status: Status,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Status {
     pub dpad_left: bool,
     pub dpad_right: bool,
@@ -35,6 +51,168 @@ pub struct Status {
     pub start: bool,
     pub select: bool,
     pub power: bool,
+    /// Number of long presses of the held wheel button (0 if short)
+    pub long_press: u32,
+}
+
+impl Status {
+    /// State of a button, None if the button is unknown
+    fn button(&mut self, code: KeyCode) -> Option<&mut bool> {
+        match code {
+            KeyCode::BTN_DPAD_LEFT => Some(&mut self.dpad_left),
+            KeyCode::BTN_DPAD_RIGHT => Some(&mut self.dpad_right),
+            KeyCode::BTN_DPAD_UP => Some(&mut self.dpad_up),
+            KeyCode::BTN_DPAD_DOWN => Some(&mut self.dpad_down),
+            KeyCode::BTN_START => Some(&mut self.start),
+            KeyCode::BTN_SELECT => Some(&mut self.select),
+            KeyCode::KEY_POWER => Some(&mut self.power),
+            _ => None,
+        }
+    }
+
+    fn is_pressed(&self) -> bool {
+        self.dpad_left
+            || self.dpad_right
+            || self.dpad_up
+            || self.dpad_down
+            || self.start
+            || self.select
+            || self.power
+    }
+
+    fn set(&mut self, code: KeyCode, pressed: bool) {
+        if let Some(button) = self.button(code) {
+            *button = pressed;
+        }
+    }
+}
+
+/// The other button of a chord
+fn partner(code: KeyCode) -> Option<KeyCode> {
+    match code {
+        KeyCode::BTN_DPAD_UP => Some(KeyCode::BTN_DPAD_DOWN),
+        KeyCode::BTN_DPAD_DOWN => Some(KeyCode::BTN_DPAD_UP),
+        KeyCode::BTN_DPAD_LEFT => Some(KeyCode::BTN_DPAD_RIGHT),
+        KeyCode::BTN_DPAD_RIGHT => Some(KeyCode::BTN_DPAD_LEFT),
+        _ => None,
+    }
+}
+
+/// Sent when a button is held (None without long press)
+fn long_code(code: KeyCode) -> Option<KeyCode> {
+    match code {
+        KeyCode::BTN_DPAD_LEFT => Some(KeyCode::KEY_REWIND),
+        KeyCode::BTN_DPAD_RIGHT => Some(KeyCode::KEY_FASTFORWARD),
+        KeyCode::BTN_START => Some(KeyCode::KEY_FAVORITES),
+        KeyCode::BTN_SELECT => Some(KeyCode::KEY_SHUFFLE),
+        _ => None,
+    }
+}
+
+fn is_wheel(code: KeyCode) -> bool {
+    code == KeyCode::BTN_DPAD_LEFT || code == KeyCode::BTN_DPAD_RIGHT
+}
+
+/// Turn the presses and the releases into the sent buttons, a chord is
+/// sent once (with both buttons in the status) without the single press
+/// of its first button.
+#[derive(Default)]
+struct Gestures {
+    status: Status,
+    /// Press not sent until the other button of the chord can no longer
+    /// join, or until it's released or long enough for a long press
+    pending: Option<(KeyCode, Instant)>,
+    /// Wheel button held and when its next long press is sent
+    held: Option<(KeyCode, Instant)>,
+    /// Buttons to send with the status of that time
+    ready: VecDeque<(KeyCode, Status)>,
+}
+
+impl Gestures {
+    fn send(&mut self, code: KeyCode) {
+        self.ready.push_back((code, self.status.clone()));
+    }
+
+    fn event(&mut self, code: KeyCode, value: i32, now: Instant) {
+        /* Ignore the unknown buttons and the autorepeat */
+        if value > 1 || self.status.button(code).is_none() {
+            return;
+        }
+
+        if value == 0 {
+            self.status.set(code, false);
+            if self.pending.is_some_and(|(pending, _)| pending == code) {
+                self.pending = None;
+                self.send(code);
+            }
+            if self.held.is_some_and(|(held, _)| held == code) {
+                self.held = None;
+            }
+            return;
+        }
+
+        let chord = match self.pending.take() {
+            Some((pending, _)) if partner(pending) == Some(code) => true,
+            Some((pending, _)) => {
+                self.send(pending);
+                false
+            }
+            None => false,
+        };
+        /* A button pressed with other ones is sent now */
+        let alone = !self.status.is_pressed();
+        self.status.set(code, true);
+        self.status.long_press = 0;
+        self.held = None;
+
+        let delay = if chord || !alone {
+            None
+        } else if long_code(code).is_some() {
+            Some(LONG_PRESS)
+        } else if partner(code).is_some() {
+            Some(CHORD)
+        } else {
+            None
+        };
+        match delay {
+            Some(delay) => self.pending = Some((code, now + delay)),
+            None => self.send(code),
+        }
+    }
+
+    /// When `timeout` must be called (None to wait on the next event)
+    fn deadline(&self) -> Option<Instant> {
+        [self.pending, self.held]
+            .into_iter()
+            .flatten()
+            .map(|(_, at)| at)
+            .min()
+    }
+
+    fn timeout(&mut self, now: Instant) {
+        if let Some((code, at)) = self.pending
+            && at <= now
+        {
+            self.pending = None;
+            match long_code(code) {
+                Some(_) if is_wheel(code) => self.held = Some((code, at)),
+                Some(long) => {
+                    self.status.long_press = 1;
+                    self.send(long);
+                }
+                None => self.send(code),
+            }
+        }
+
+        if let Some((code, at)) = self.held
+            && at <= now
+            && let Some(long) = long_code(code)
+        {
+            self.held = Some((code, at + LONG_PRESS_REPEAT));
+            self.status.long_press += 1;
+            self.send(long);
+        }
+    }
 }
 
 impl Buttons {
@@ -47,20 +225,11 @@ impl Buttons {
         let event = epoll::EpollEvent::new(epoll::EpollFlags::EPOLLIN, 0);
         epoll.add(&device, event)?;
 
-        let status = Status {
-            dpad_left: false,
-            dpad_right: false,
-            dpad_up: false,
-            dpad_down: false,
-            start: false,
-            select: false,
-            power: false,
-        };
-
         Ok(Self {
             device,
             epoll,
-            status,
+            gestures: Gestures::default(),
+            status: Status::default(),
         })
     }
 
@@ -72,48 +241,29 @@ impl Buttons {
         let mut events = [epoll::EpollEvent::empty(); 2];
 
         loop {
+            if let Some((code, status)) = self.gestures.ready.pop_front() {
+                self.status = status;
+                return Ok(code);
+            }
+
             match self.device.fetch_events() {
                 Ok(events) => {
+                    let now = Instant::now();
                     for ev in events {
-                        let value = ev.value();
-                        let code = KeyCode::new(ev.code());
-                        if match code {
-                            KeyCode::BTN_DPAD_LEFT => {
-                                self.status.dpad_left = value == 1;
-                                self.status.dpad_left
-                            }
-                            KeyCode::BTN_DPAD_RIGHT => {
-                                self.status.dpad_right = value == 1;
-                                self.status.dpad_right
-                            }
-                            KeyCode::BTN_DPAD_UP => {
-                                self.status.dpad_up = value == 1;
-                                self.status.dpad_up
-                            }
-                            KeyCode::BTN_DPAD_DOWN => {
-                                self.status.dpad_down = value == 1;
-                                self.status.dpad_down
-                            }
-                            KeyCode::BTN_START => {
-                                self.status.start = value == 1;
-                                self.status.start
-                            }
-                            KeyCode::BTN_SELECT => {
-                                self.status.select = value == 1;
-                                self.status.select
-                            }
-                            KeyCode::KEY_POWER => {
-                                self.status.power = value == 1;
-                                self.status.power
-                            }
-                            _ => false,
-                        } {
-                            return Ok(code);
-                        }
+                        self.gestures
+                            .event(KeyCode::new(ev.code()), ev.value(), now);
                     }
                 }
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    self.epoll.wait(&mut events, epoll::EpollTimeout::NONE)?;
+                    let timeout = match self.gestures.deadline() {
+                        Some(at) => epoll::EpollTimeout::try_from(
+                            at.saturating_duration_since(Instant::now()),
+                        )?,
+                        None => epoll::EpollTimeout::NONE,
+                    };
+                    if self.epoll.wait(&mut events, timeout)? == 0 {
+                        self.gestures.timeout(Instant::now());
+                    }
                 }
                 Err(e) => {
                     return Err(Box::new(e));
@@ -122,3 +272,106 @@ impl Buttons {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chord() {
+        let mut gestures = Gestures::default();
+        let start = Instant::now();
+        let ms = |ms: u64| start + Duration::from_millis(ms);
+        let sent = |gestures: &mut Gestures| -> Vec<KeyCode> {
+            gestures.ready.drain(..).map(|(code, _)| code).collect()
+        };
+
+        /* UP and DOWN together, only the chord is sent */
+        gestures.event(KeyCode::BTN_DPAD_UP, 1, ms(0));
+        assert_eq!(gestures.deadline(), Some(ms(150)));
+        gestures.event(KeyCode::BTN_DPAD_DOWN, 1, ms(50));
+        let (code, status) = gestures.ready.pop_front().expect("no chord");
+        assert_eq!(code, KeyCode::BTN_DPAD_DOWN);
+        assert!(status.dpad_up && status.dpad_down);
+        gestures.event(KeyCode::BTN_DPAD_UP, 0, ms(100));
+        gestures.event(KeyCode::BTN_DPAD_DOWN, 0, ms(100));
+        gestures.timeout(ms(500));
+        assert_eq!(sent(&mut gestures), []);
+
+        /* A single press is sent on release or after the delay */
+        gestures.event(KeyCode::BTN_DPAD_UP, 1, ms(1000));
+        gestures.event(KeyCode::BTN_DPAD_UP, 0, ms(1050));
+        assert_eq!(sent(&mut gestures), [KeyCode::BTN_DPAD_UP]);
+        gestures.event(KeyCode::BTN_DPAD_DOWN, 1, ms(2000));
+        gestures.timeout(ms(2100));
+        assert_eq!(sent(&mut gestures), []);
+        gestures.timeout(ms(2150));
+        assert_eq!(sent(&mut gestures), [KeyCode::BTN_DPAD_DOWN]);
+        gestures.event(KeyCode::BTN_DPAD_UP, 1, ms(2200));
+        gestures.event(KeyCode::BTN_DPAD_UP, 0, ms(2250));
+        assert_eq!(sent(&mut gestures), [KeyCode::BTN_DPAD_UP]);
+
+        /* Another button doesn't wait */
+        gestures.event(KeyCode::BTN_DPAD_DOWN, 0, ms(3000));
+        gestures.event(KeyCode::BTN_DPAD_UP, 1, ms(3000));
+        gestures.event(KeyCode::BTN_START, 1, ms(3010));
+        assert_eq!(
+            sent(&mut gestures),
+            [KeyCode::BTN_DPAD_UP, KeyCode::BTN_START]
+        );
+        gestures.event(KeyCode::BTN_DPAD_UP, 0, ms(3100));
+        gestures.event(KeyCode::BTN_START, 0, ms(3100));
+        assert_eq!(sent(&mut gestures), []);
+
+        /* LEFT and RIGHT together, the wheel is not turned */
+        gestures.event(KeyCode::BTN_DPAD_RIGHT, 1, ms(4000));
+        gestures.event(KeyCode::BTN_DPAD_LEFT, 1, ms(4100));
+        let (code, status) = gestures.ready.pop_front().expect("no chord");
+        assert_eq!(code, KeyCode::BTN_DPAD_LEFT);
+        assert!(status.dpad_left && status.dpad_right);
+        gestures.timeout(ms(5000));
+        assert_eq!(sent(&mut gestures), []);
+        gestures.event(KeyCode::BTN_DPAD_LEFT, 0, ms(5000));
+        gestures.event(KeyCode::BTN_DPAD_RIGHT, 0, ms(5000));
+
+        /* The wheel is sent on release, or it's a long press */
+        gestures.event(KeyCode::BTN_DPAD_RIGHT, 1, ms(6000));
+        gestures.timeout(ms(6300));
+        gestures.event(KeyCode::BTN_DPAD_RIGHT, 0, ms(6400));
+        assert_eq!(sent(&mut gestures), [KeyCode::BTN_DPAD_RIGHT]);
+        gestures.event(KeyCode::BTN_DPAD_LEFT, 1, ms(7000));
+        assert_eq!(gestures.deadline(), Some(ms(7600)));
+        gestures.timeout(ms(7600));
+        gestures.timeout(ms(8000));
+        let long_presses: Vec<(KeyCode, u32)> = gestures
+            .ready
+            .drain(..)
+            .map(|(code, status)| (code, status.long_press))
+            .collect();
+        assert_eq!(
+            long_presses,
+            [(KeyCode::KEY_REWIND, 1), (KeyCode::KEY_REWIND, 2)]
+        );
+        gestures.event(KeyCode::BTN_DPAD_LEFT, 0, ms(8100));
+        assert_eq!((sent(&mut gestures), gestures.deadline()), (vec![], None));
+
+        /* OK is held once */
+        gestures.event(KeyCode::BTN_START, 1, ms(9000));
+        gestures.timeout(ms(9600));
+        gestures.timeout(ms(10000));
+        gestures.event(KeyCode::BTN_START, 0, ms(10100));
+        assert_eq!(sent(&mut gestures), [KeyCode::KEY_FAVORITES]);
+        gestures.event(KeyCode::BTN_START, 1, ms(11000));
+        gestures.event(KeyCode::BTN_START, 0, ms(11100));
+        assert_eq!(sent(&mut gestures), [KeyCode::BTN_START]);
+
+        /* HOME too */
+        gestures.event(KeyCode::BTN_SELECT, 1, ms(12000));
+        gestures.timeout(ms(12600));
+        gestures.event(KeyCode::BTN_SELECT, 0, ms(12700));
+        assert_eq!(sent(&mut gestures), [KeyCode::KEY_SHUFFLE]);
+        gestures.event(KeyCode::BTN_SELECT, 1, ms(13000));
+        gestures.event(KeyCode::BTN_SELECT, 0, ms(13100));
+        assert_eq!(sent(&mut gestures), [KeyCode::BTN_SELECT]);
+    }
+}
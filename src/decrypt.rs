@@ -15,13 +15,135 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::fs::File;
-use std::io::{Read, Result, Seek, SeekFrom};
+use aes::Aes128;
+use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit, block_padding::NoPadding};
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
 use std::path::Path;
 
+use crate::archive::ZipEntry;
+
+/// XXTEA key, the 16 bytes are read as big-endian words
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Key([u32; 4]);
+
+impl Key {
+    /* Original key (big-endian):
+     * 0x91, 0xBD, 0x7A, 0x0A, 0xA7, 0x54, 0x40, 0xA9,
+     * 0xBB, 0xD4, 0x9D, 0x6C, 0xE0, 0xDC, 0xC0, 0xE3,
+     * See https://github.com/marian-m12l/studio/blob/028912d9ee06e77bff679abd31701aa493f5461a/core/src/main/java/studio/core/v1/utils/XXTEACipher.java
+     */
+    pub const GENERIC: Key = Key([0x91BD7A0A, 0xA75440A9, 0xBBD49D6C, 0xE0DCC0E3]);
+
+    pub fn from_bytes(bytes: &[u8; 16]) -> Self {
+        let mut key = [0u32; 4];
+        for (word, chunk) in key.iter_mut().zip(bytes.chunks_exact(4)) {
+            *word = u32::from_be_bytes(chunk.try_into().unwrap());
+        }
+        Key(key)
+    }
+
+    /// Read a raw key file (16 bytes)
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let bytes: [u8; 16] = fs::read(path)?
+            .try_into()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "The key must be 16 bytes"))?;
+        Ok(Self::from_bytes(&bytes))
+    }
+
+    /// Compute the device key from the `.md` file at the root of a Lunii.
+    /// The device UUID (0x100..0x200) is decrypted with the generic key,
+    /// then the two halves are swapped.
+    pub fn from_device_metadata(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path)?;
+        let uuid = bytes
+            .get(0x100..0x200)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "The device UUID is missing"))?;
+
+        let decrypted = decrypt_block(uuid, &Key::GENERIC);
+        let word = |i: usize| u32::from_le_bytes(decrypted[i * 4..i * 4 + 4].try_into().unwrap());
+        Ok(Key([word(2), word(3), word(0), word(1)]))
+    }
+}
+
+/// Cipher of the story FS files, only the first 512 bytes are encrypted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cipher {
+    /// Lunii v1 and v2 packs
+    Xxtea(Key),
+    /// Lunii v3 packs (AES-128-CBC)
+    Aes { key: [u8; 16], iv: [u8; 16] },
+}
+
+impl Cipher {
+    pub const GENERIC: Cipher = Cipher::Xxtea(Key::GENERIC);
+
+    pub fn from_aes_bytes(bytes: &[u8; 32]) -> Self {
+        let (key, iv) = bytes.split_at(16);
+        Cipher::Aes {
+            key: key.try_into().unwrap(),
+            iv: iv.try_into().unwrap(),
+        }
+    }
+
+    /// Read the key of a device: the key of a Lunii v3 (AES key then IV,
+    /// 32 bytes), the XXTEA key of a Lunii v2 (16 bytes) or its `.md` file
+    pub fn from_device_key_file(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path)?;
+        match bytes.len() {
+            16 => Ok(Cipher::Xxtea(Key::from_file(path)?)),
+            32 => Ok(Self::from_aes_bytes(bytes.as_slice().try_into().unwrap())),
+            len if len >= 0x200 => Ok(Cipher::Xxtea(Key::from_device_metadata(path)?)),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "The device key must be 32 bytes (AES key and IV), 16 bytes (XXTEA key) or the .md file",
+            )),
+        }
+    }
+
+    pub(super) fn decrypt_block(&self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Cipher::Xxtea(key) => decrypt_block(bytes, key),
+            Cipher::Aes { key, iv } => {
+                let mut result = bytes.to_vec();
+                let size = std::cmp::min(512, bytes.len()) / 16 * 16;
+                cbc::Decryptor::<Aes128>::new(key.into(), iv.into())
+                    .decrypt_padded_mut::<NoPadding>(&mut result[..size])
+                    .expect("aligned block");
+                result
+            }
+        }
+    }
+
+    pub(super) fn encrypt_block(&self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Cipher::Xxtea(key) => encrypt_block(bytes, key),
+            Cipher::Aes { key, iv } => {
+                let mut result = bytes.to_vec();
+                let size = std::cmp::min(512, bytes.len()) / 16 * 16;
+                cbc::Encryptor::<Aes128>::new(key.into(), iv.into())
+                    .encrypt_padded_mut::<NoPadding>(&mut result[..size], size)
+                    .expect("aligned block");
+                result
+            }
+        }
+    }
+}
+
 pub enum FileReader {
     Encrypted(DecryptedFile),
     Plain(File),
+    Zip(ZipEntry),
+}
+
+impl FileReader {
+    /// Size of the (decrypted) file in bytes
+    pub fn size(&mut self) -> Result<u64> {
+        let position = self.stream_position()?;
+        let size = self.seek(SeekFrom::End(0))?;
+        self.seek(SeekFrom::Start(position))?;
+        Ok(size)
+    }
 }
 
 impl Read for FileReader {
@@ -29,6 +151,7 @@ impl Read for FileReader {
         match self {
             FileReader::Encrypted(file) => file.read(buf),
             FileReader::Plain(file) => file.read(buf),
+            FileReader::Zip(entry) => entry.read(buf),
         }
     }
 }
@@ -38,6 +161,7 @@ impl Seek for FileReader {
         match self {
             FileReader::Encrypted(file) => file.seek(pos),
             FileReader::Plain(file) => file.seek(pos),
+            FileReader::Zip(entry) => entry.seek(pos),
         }
     }
 }
@@ -49,7 +173,12 @@ pub struct DecryptedFile {
 }
 
 impl DecryptedFile {
+    /// Open a file encrypted with the generic key
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(path, &Cipher::GENERIC)
+    }
+
+    pub fn open_with(path: impl AsRef<Path>, cipher: &Cipher) -> Result<Self> {
         let mut file = File::open(path)?;
 
         /* Read max 512 bytes */
@@ -57,7 +186,7 @@ impl DecryptedFile {
         let bytes_read = file.read(&mut header)?;
         header.truncate(bytes_read);
 
-        let decrypted_header = decrypt_block(&header);
+        let decrypted_header = cipher.decrypt_block(&header);
         file.seek(SeekFrom::Start(0))?;
 
         Ok(Self {
@@ -114,13 +243,38 @@ impl Seek for DecryptedFile {
     }
 }
 
-fn btea_decrypt(v: &mut [u32], k: &[u32; 4]) {
+const DELTA: u32 = 0x9E3779B9;
+
+fn btea_encrypt(v: &mut [u32], k: &[u32; 4]) {
     let n = v.len();
     if n < 2 {
         return;
     }
 
-    const DELTA: u32 = 0x9E3779B9;
+    /* Same number of rounds than btea_decrypt */
+    let rounds = 1 + 52 / n;
+    let mut sum: u32 = 0;
+    let mut z = v[n - 1];
+
+    for _ in 0..rounds {
+        sum = sum.wrapping_add(DELTA);
+        let e = (sum >> 2) & 3;
+
+        for p in 0..n {
+            let y = v[(p + 1) % n];
+            let mx = (((z >> 5) ^ (y << 2)).wrapping_add((y >> 3) ^ (z << 4)))
+                ^ ((sum ^ y).wrapping_add(k[(((p as u32) & 3) ^ e) as usize] ^ z));
+            v[p] = v[p].wrapping_add(mx);
+            z = v[p];
+        }
+    }
+}
+
+fn btea_decrypt(v: &mut [u32], k: &[u32; 4]) {
+    let n = v.len();
+    if n < 2 {
+        return;
+    }
 
     /* WARNING: Lunii is using 1+52/n instead of 6+52/n
      * See https://github.com/marian-m12l/studio/issues/292#issuecomment-1157586816
@@ -150,16 +304,9 @@ fn btea_decrypt(v: &mut [u32], k: &[u32; 4]) {
     }
 }
 
-pub(super) fn decrypt_block(bytes: &Vec<u8>) -> Vec<u8> {
+pub(super) fn decrypt_block(bytes: &[u8], key: &Key) -> Vec<u8> {
     use byteorder::{ByteOrder, LittleEndian};
 
-    /* Original key (big-endian):
-     * 0x91, 0xBD, 0x7A, 0x0A, 0xA7, 0x54, 0x40, 0xA9,
-     * 0xBB, 0xD4, 0x9D, 0x6C, 0xE0, 0xDC, 0xC0, 0xE3,
-     * See https://github.com/marian-m12l/studio/blob/028912d9ee06e77bff679abd31701aa493f5461a/core/src/main/java/studio/core/v1/utils/XXTEACipher.java
-     */
-    const KEY: [u32; 4] = [0x91BD7A0A, 0xA75440A9, 0xBBD49D6C, 0xE0DCC0E3];
-
     /* Only the first 512 bytes are encrypted */
     let block_size = std::cmp::min(512, bytes.len());
     let aligned_size = (block_size / 4) * 4;
@@ -174,7 +321,7 @@ pub(super) fn decrypt_block(bytes: &Vec<u8>) -> Vec<u8> {
 
     /* (max 128 u32) */
     let n = std::cmp::min(128, int_count);
-    btea_decrypt(&mut v[0..n], &KEY);
+    btea_decrypt(&mut v[0..n], &key.0);
 
     /* Convert to little-endian */
     let mut result = vec![0u8; aligned_size];
@@ -186,3 +333,181 @@ pub(super) fn decrypt_block(bytes: &Vec<u8>) -> Vec<u8> {
 
     result
 }
+
+/// Inverse of decrypt_block
+pub(super) fn encrypt_block(bytes: &[u8], key: &Key) -> Vec<u8> {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    /* Only the first 512 bytes are encrypted */
+    let block_size = std::cmp::min(512, bytes.len());
+    let aligned_size = (block_size / 4) * 4;
+    if aligned_size < 4 {
+        return bytes.to_vec();
+    }
+
+    let int_count = aligned_size / 4;
+    let mut v = vec![0u32; int_count];
+    LittleEndian::read_u32_into(&bytes[0..aligned_size], &mut v);
+    btea_encrypt(&mut v, &key.0);
+
+    let mut result = vec![0u8; aligned_size];
+    LittleEndian::write_u32_into(&v, &mut result);
+
+    if bytes.len() > aligned_size {
+        result.extend_from_slice(&bytes[aligned_size..]);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn seek() {
+        let bytes: Vec<u8> = (0..2048).map(|i| (i % 251) as u8).collect();
+        let mut file = tempfile::NamedTempFile::new().expect("cannot create temp file");
+        file.write_all(&bytes).expect("cannot write temp file");
+
+        let mut reader =
+            FileReader::Encrypted(DecryptedFile::open(file.path()).expect("cannot open file"));
+        assert_eq!(reader.size().expect("cannot get size"), 2048);
+
+        /* After the encrypted header, the bytes are plain */
+        let mut buf = [0u8; 16];
+        reader.seek(SeekFrom::Start(1000)).expect("cannot seek");
+        reader.read_exact(&mut buf).expect("cannot read");
+        assert_eq!(buf, bytes[1000..1016]);
+
+        /* Through the header */
+        let header = decrypt_block(&bytes[..512], &Key::GENERIC);
+        let mut buf = [0u8; 32];
+        reader.seek(SeekFrom::Start(500)).expect("cannot seek");
+        reader.read_exact(&mut buf).expect("cannot read");
+        assert_eq!(buf[..12], header[500..512]);
+        assert_eq!(buf[12..], bytes[512..532]);
+        assert_eq!(reader.stream_position().expect("cannot get position"), 532);
+    }
+
+    #[test]
+    fn encrypt() {
+        let bytes: Vec<u8> = (0..1024).map(|i| (i % 251) as u8).collect();
+
+        let encrypted = encrypt_block(&bytes, &Key::GENERIC);
+        assert_ne!(encrypted[..512], bytes[..512]);
+        assert_eq!(encrypted[512..], bytes[512..]);
+        assert_eq!(decrypt_block(&encrypted, &Key::GENERIC), bytes);
+
+        /* Short and unaligned blocks */
+        for len in [3, 8, 13, 510] {
+            let encrypted = encrypt_block(&bytes[..len], &Key::GENERIC);
+            assert_eq!(encrypted.len(), len);
+            assert_eq!(decrypt_block(&encrypted, &Key::GENERIC), bytes[..len]);
+        }
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn known_answer() {
+        let plain: Vec<u8> = (0..32).collect();
+
+        let expected = hex("f365fc7bbc23ef8cda0849155272f5342758d2757f08c42c254397339b3bd246");
+        assert_eq!(encrypt_block(&plain, &Key::GENERIC), expected);
+        assert_eq!(decrypt_block(&expected, &Key::GENERIC), plain);
+
+        let key = Key::from_bytes(&std::array::from_fn(|i| i as u8));
+        let expected = hex("1ca90f798fbb9ba9633680061dde0d6ad3ed6931e3bfb1102eb62c8fb3775acc");
+        assert_eq!(encrypt_block(&plain, &key), expected);
+        assert_eq!(decrypt_block(&expected, &key), plain);
+    }
+
+    #[test]
+    fn keys() {
+        let generic = [
+            0x91, 0xBD, 0x7A, 0x0A, 0xA7, 0x54, 0x40, 0xA9, 0xBB, 0xD4, 0x9D, 0x6C, 0xE0, 0xDC,
+            0xC0, 0xE3,
+        ];
+        let mut file = tempfile::NamedTempFile::new().expect("cannot create temp file");
+        file.write_all(&generic).expect("cannot write temp file");
+        let key = Key::from_file(file.path()).expect("cannot read key");
+        assert_eq!(key, Key::GENERIC);
+
+        file.write_all(&[0]).expect("cannot write temp file");
+        assert!(Key::from_file(file.path()).is_err());
+
+        /* Device metadata with the (encrypted) UUID at 0x100 */
+        let mut md = vec![0u8; 0x100];
+        md.extend((0..256).map(|i| (i * 7 % 256) as u8));
+        let mut file = tempfile::NamedTempFile::new().expect("cannot create temp file");
+        file.write_all(&md[..0x180])
+            .expect("cannot write temp file");
+        assert!(Key::from_device_metadata(file.path()).is_err());
+        file.write_all(&md[0x180..])
+            .expect("cannot write temp file");
+        let key = Key::from_device_metadata(file.path()).expect("cannot read device key");
+        assert_eq!(key, Key([0xc33ef07c, 0xd976fc31, 0x181af690, 0x2b23149d]));
+
+        /* The files are decrypted with the device key */
+        let bytes: Vec<u8> = (0..600).map(|i| (i % 251) as u8).collect();
+        let mut file = tempfile::NamedTempFile::new().expect("cannot create temp file");
+        file.write_all(&encrypt_block(&bytes, &key))
+            .expect("cannot write temp file");
+        let mut decrypted = Vec::new();
+        DecryptedFile::open_with(file.path(), &Cipher::Xxtea(key))
+            .expect("cannot open file")
+            .read_to_end(&mut decrypted)
+            .expect("cannot read");
+        assert_eq!(decrypted, bytes);
+    }
+
+    #[test]
+    fn aes() {
+        let plain: Vec<u8> = (0..40).collect();
+        let cipher = Cipher::from_aes_bytes(&std::array::from_fn(|i| match i {
+            0..16 => i as u8,
+            _ => 31 - i as u8,
+        }));
+
+        /* The last 8 bytes are not aligned on the AES block */
+        let mut expected = hex("03a9c8fe778fb8a8668359542ad4d584bce873fe4bc2ba36d6d8742b27cdd457");
+        expected.extend_from_slice(&plain[32..]);
+        assert_eq!(cipher.encrypt_block(&plain), expected);
+        assert_eq!(cipher.decrypt_block(&expected), plain);
+
+        let mut file = tempfile::NamedTempFile::new().expect("cannot create temp file");
+        file.write_all(&[0; 31]).expect("cannot write temp file");
+        assert!(Cipher::from_device_key_file(file.path()).is_err());
+        file.write_all(&[0]).expect("cannot write temp file");
+        let device = Cipher::from_device_key_file(file.path()).expect("cannot read device key");
+        assert_eq!(
+            device,
+            Cipher::Aes {
+                key: [0; 16],
+                iv: [0; 16]
+            }
+        );
+    }
+
+    #[test]
+    fn device_key_file() {
+        let mut file = tempfile::NamedTempFile::new().expect("cannot create temp file");
+        file.write_all(&[0; 16]).expect("cannot write temp file");
+        let device = Cipher::from_device_key_file(file.path()).expect("cannot read device key");
+        assert_eq!(device, Cipher::Xxtea(Key([0; 4])));
+
+        /* The .md file of the device */
+        let mut file = tempfile::NamedTempFile::new().expect("cannot create temp file");
+        file.write_all(&[0; 0x200]).expect("cannot write temp file");
+        let device = Cipher::from_device_key_file(file.path()).expect("cannot read device key");
+        let key = Key::from_device_metadata(file.path()).expect("cannot read device key");
+        assert_eq!(device, Cipher::Xxtea(key));
+    }
+}
@@ -0,0 +1,146 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use clap::ValueEnum;
+use std::{sync::Arc, time::Duration};
+
+use crate::timeout::Timeout;
+
+/// Backlight when the device is idle
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum IdleScreen {
+    /// Lower the brightness
+    Dim,
+    /// Turn off the backlight
+    #[default]
+    Off,
+}
+
+type Callback = Arc<dyn Fn() + Send + Sync>;
+
+/// Idle timers, they are restarted on every activity. The callbacks are
+/// called when the screen must be dimmed and when the device must power
+/// off. A zero delay disables a timer.
+pub struct Idle {
+    screen_delay: Duration,
+    poweroff_delay: Duration,
+    on_screen: Callback,
+    on_poweroff: Callback,
+    screen: Option<Timeout>,
+    poweroff: Option<Timeout>,
+    asleep: bool,
+}
+
+fn restart(timeout: &mut Option<Timeout>, delay: Duration, callback: &Callback) {
+    if let Some(timeout) = timeout {
+        timeout.clear();
+    }
+    if delay.is_zero() {
+        *timeout = None;
+        return;
+    }
+
+    let callback = callback.clone();
+    *timeout = Some(Timeout::set(delay, move || callback()));
+}
+
+impl Idle {
+    pub fn new<S, P>(
+        screen_delay: Duration,
+        poweroff_delay: Duration,
+        on_screen: S,
+        on_poweroff: P,
+    ) -> Self
+    where
+        S: Fn() + Send + Sync + 'static,
+        P: Fn() + Send + Sync + 'static,
+    {
+        Self {
+            screen_delay,
+            poweroff_delay,
+            on_screen: Arc::new(on_screen),
+            on_poweroff: Arc::new(on_poweroff),
+            screen: None,
+            poweroff: None,
+            asleep: false,
+        }
+    }
+
+    /// Restart the screen timer (for example if the audio is still playing)
+    pub fn screen_timer(&mut self) {
+        restart(&mut self.screen, self.screen_delay, &self.on_screen);
+    }
+
+    /// Restart the poweroff timer
+    pub fn poweroff_timer(&mut self) {
+        restart(&mut self.poweroff, self.poweroff_delay, &self.on_poweroff);
+    }
+
+    /// A button was pressed or a stage was shown
+    pub fn activity(&mut self) {
+        self.screen_timer();
+        self.poweroff_timer();
+    }
+
+    /// The screen is dimmed (or off) because of the idle timer
+    pub fn sleep(&mut self) {
+        self.asleep = true;
+    }
+
+    /// It returns true if the screen was asleep
+    pub fn wake(&mut self) -> bool {
+        std::mem::take(&mut self.asleep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn idle() {
+        let (tx, rx) = channel();
+        let tx_poweroff = tx.clone();
+        let mut idle = Idle::new(
+            Duration::from_millis(20),
+            Duration::from_millis(60),
+            move || tx.send("screen").unwrap(),
+            move || tx_poweroff.send("poweroff").unwrap(),
+        );
+
+        idle.activity();
+        let timeout = Duration::from_secs(1);
+        assert_eq!(rx.recv_timeout(timeout), Ok("screen"));
+        idle.sleep();
+        assert_eq!(rx.recv_timeout(timeout), Ok("poweroff"));
+
+        /* An activity restarts the timers */
+        assert!(idle.wake());
+        assert!(!idle.wake());
+        idle.activity();
+        idle.activity();
+        assert_eq!(rx.recv_timeout(timeout), Ok("screen"));
+        assert_eq!(rx.recv_timeout(timeout), Ok("poweroff"));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        /* Disabled timers */
+        let mut idle = Idle::new(Duration::ZERO, Duration::ZERO, || {}, || {});
+        idle.activity();
+        assert!(idle.screen.is_none() && idle.poweroff.is_none());
+    }
+}
@@ -15,23 +15,62 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+mod archive;
 mod book;
 mod books;
 mod buttons;
 mod decrypt;
+mod idle;
+mod night;
+mod order;
+mod output;
+mod parental;
 mod player;
+mod playlist;
 mod screen;
 mod services;
+mod sleep;
+mod state;
 mod timeout;
+mod volume;
+mod watcher;
 
 pub use book::Book;
+pub use book::BookInfo;
 pub use book::ControlSettings;
+pub use book::GraphFormat;
+pub use book::Issue;
+pub use book::Loudness;
+pub use book::Severity;
+pub use book::Source;
 pub use book::Stage;
 pub use books::Books;
 pub use buttons::Buttons;
 pub use buttons::Status;
+pub use decrypt::Cipher;
 pub use decrypt::FileReader;
+pub use decrypt::Key;
+pub use idle::Idle;
+pub use idle::IdleScreen;
+pub use night::Night;
+pub use night::NightBooks;
+pub use night::Schedule;
+pub use order::Order;
+pub use order::Ordering;
+pub use output::Output;
+pub use parental::Limit;
+pub use parental::Limits;
+pub use parental::Parental;
 pub use player::Player;
+pub use playlist::Item;
+pub use playlist::Playlist;
+pub use playlist::Target;
 pub use screen::Screen;
 pub use services::Services;
+pub use sleep::Sleep;
+pub use state::Position;
+pub use state::Resume;
+pub use state::State;
 pub use timeout::Timeout;
+pub use volume::Volume;
+pub use watcher::Watcher;
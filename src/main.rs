@@ -16,19 +16,23 @@
  */
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use evdev::KeyCode;
 use signal_hook::{consts::*, iterator::Signals};
+use std::collections::HashSet;
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{error::Error, thread};
 
 use contelia::{
-    Books, Buttons, ControlSettings, FileReader, Player, Screen, Services, Stage, Status, Timeout,
+    Book, Books, Buttons, Cipher, ControlSettings, FileReader, GraphFormat, Idle, IdleScreen,
+    Night, NightBooks, Order, Output, Parental, Player, Resume, Schedule, Screen, Services,
+    Severity, Sleep, Source, Stage, Status, Timeout, Volume, Watcher,
 };
 
 #[derive(Debug, PartialEq)]
@@ -40,11 +44,29 @@ enum Next {
     Volume,
     Pause,
     Play,
+    Progress,
+    Night,
+    Favorite,
+    Shuffle,
+    Sleep,
+    TimesUp,
     Timeout,
     Settings,
     Shutdown,
 }
 
+/// Gesture used to go back to the previous menu
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Back {
+    /// No back action
+    Disabled,
+    /// The HOME button goes back (the home transition is used when there is
+    /// nothing to go back to)
+    Home,
+    /// Press UP and DOWN together
+    Volume,
+}
+
 fn is_key_enabled(control_settings: &ControlSettings, code: KeyCode) -> bool {
     match code {
         KeyCode::BTN_DPAD_LEFT => control_settings.wheel,
@@ -56,22 +78,50 @@ fn is_key_enabled(control_settings: &ControlSettings, code: KeyCode) -> bool {
     }
 }
 
+/// Seek step (in seconds) of a long press on the wheel, faster after a few
+/// repeats
+const SEEK_STEP: i64 = 10;
+const SEEK_FAST_STEP: i64 = 30;
+const SEEK_FAST_AFTER: u32 = 3;
+
+/// Button sent at the end of the audio of a stage
+fn eos_code(control_settings: &ControlSettings) -> Option<KeyCode> {
+    if control_settings.ok || control_settings.autoplay {
+        Some(KeyCode::BTN_START)
+    } else if control_settings.home {
+        Some(KeyCode::BTN_SELECT)
+    } else {
+        None
+    }
+}
+
 /// Process the event and returns true is we want to skip the assets
 fn process_event(
     books: &mut Books,
     player: &mut Player,
     state: &Stage,
     code: KeyCode,
+    status: Option<&Status>,
     autoplay: bool,
+    back: Back,
 ) -> Next {
     /* In case of autoplay or square_one, we ignore the button settings */
     if !autoplay && !state.square_one && !is_key_enabled(&state.control_settings, code) {
         return Next::Timeout;
     }
+    let playlist = books.is_playlist();
     let Some(book) = books.get() else {
         return Next::Timeout;
     };
     match code {
+        KeyCode::BTN_DPAD_LEFT | KeyCode::BTN_DPAD_RIGHT
+            if state.square_one
+                && status.is_some_and(|status| status.dpad_left && status.dpad_right) =>
+        {
+            // Surprise me: a random story of a random book
+            books.surprise();
+            Next::Normal
+        }
         KeyCode::BTN_DPAD_LEFT => {
             if state.square_one {
                 books.button_wheel_left();
@@ -98,6 +148,21 @@ fn process_event(
             }
             Next::Normal
         }
+        KeyCode::BTN_DPAD_UP | KeyCode::BTN_DPAD_DOWN
+            if state.square_one
+                && status.is_some_and(|status| status.dpad_up && status.dpad_down) =>
+        {
+            Next::Sleep
+        }
+        KeyCode::BTN_DPAD_UP | KeyCode::BTN_DPAD_DOWN
+            if back == Back::Volume
+                && status.is_some_and(|status| status.dpad_up && status.dpad_down) =>
+        {
+            match book.back() {
+                Some(_) => Next::Normal,
+                None => Next::Timeout,
+            }
+        }
         KeyCode::BTN_DPAD_UP => {
             player.volume_up();
             Next::Volume
@@ -106,24 +171,100 @@ fn process_event(
             player.volume_down();
             Next::Volume
         }
+        KeyCode::BTN_SELECT if playlist && !state.square_one => {
+            // Leave the playlist
+            books.playlist_reset();
+            Next::Normal
+        }
         KeyCode::BTN_SELECT => {
             if state.square_one {
-                Next::None
+                Next::Night
             } else {
-                book.button_home();
+                if back != Back::Home || book.back().is_none() {
+                    book.button_home();
+                }
                 Next::Normal
             }
         }
+        KeyCode::BTN_START if playlist && state.square_one => {
+            books.playlist_next();
+            Next::Normal
+        }
         KeyCode::BTN_START => {
             book.button_ok();
             Next::Normal
         }
+        KeyCode::KEY_FAVORITES => Next::Favorite,
+        KeyCode::KEY_SHUFFLE => Next::Shuffle,
         _ => Next::Timeout,
     }
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Validate the story graphs and the assets
+    Check {
+        /// A book or the books directory
+        path: PathBuf,
+    },
+    /// Show the metadata of the books
+    Info {
+        /// Print the metadata as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Compute the total duration of the audio (slow)
+        #[arg(long)]
+        duration: bool,
+
+        /// A book or the books directory
+        path: PathBuf,
+    },
+    /// Export the story graph of a book
+    Graph {
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+
+        /// The path to the book
+        path: PathBuf,
+    },
+    /// Measure the loudness of the books (the gains are cached in the books
+    /// directory, only the new or changed books are analyzed)
+    Normalize {
+        /// The books directory
+        path: PathBuf,
+    },
+    /// List the audio output devices
+    Devices,
+    /// Convert a book (like a Lunii story FS) to a STUdio story.json folder
+    Convert {
+        /// Transcode the images to PNG
+        #[arg(long)]
+        png: bool,
+
+        /// Transcode the audio assets to OGG (oggenc is required)
+        #[arg(long, conflicts_with = "fs")]
+        ogg: bool,
+
+        /// Write a Lunii story FS instead of a STUdio folder
+        #[arg(long, conflicts_with = "png")]
+        fs: bool,
+
+        /// The path to the book
+        path: PathBuf,
+
+        /// The new STUdio book folder
+        dest: PathBuf,
+    },
+}
+
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Framebuffer device
     #[arg(short, long, default_value = "/dev/fb2")]
     fb: PathBuf,
@@ -136,12 +277,293 @@ struct Cli {
     #[arg(short, long, default_value = "/dev/input/pisugar")]
     power: PathBuf,
 
+    /// Positions restored when the books are loaded
+    #[arg(short, long, value_enum, default_value_t = Resume::All)]
+    resume: Resume,
+
+    /// Order of the books (the pinned and favorite books are always first)
+    #[arg(short, long, value_enum, default_value_t = Order::Name)]
+    order: Order,
+
+    /// The wheel on the cover selects a random book (LEFT and RIGHT together
+    /// on the cover always play a random story of a random book). A long
+    /// press on HOME on the cover toggles it.
+    #[arg(long)]
+    shuffle: bool,
+
+    /// Play every audio at the same loudness (the books not analyzed yet
+    /// are analyzed in the background)
+    #[arg(long)]
+    normalize: bool,
+
+    /// Audio output device (see the devices command), the default device
+    /// is used when it's not available
+    #[arg(long)]
+    audio_device: Option<String>,
+
+    /// ALSA mixer control for the volume (like PCM), instead of scaling
+    /// the audio
+    #[arg(long)]
+    mixer: Option<String>,
+
+    /// ALSA card of the mixer control
+    #[arg(long, default_value = "default")]
+    mixer_card: String,
+
+    /// Crossfade between the menu prompts played one after the other (in
+    /// milliseconds, 0 keeps them gapless)
+    #[arg(long, default_value_t = 0)]
+    crossfade: u64,
+
+    /// Gesture used to go back to the previous menu
+    #[arg(short, long, value_enum, default_value_t = Back::Volume)]
+    back: Back,
+
+    /// Night time, like 19:30-07:00 (HOME on the cover toggles the night mode)
+    #[arg(short, long)]
+    night: Option<Schedule>,
+
+    /// Books without night mode, when the night mode is enabled
+    #[arg(long, value_enum, default_value_t = NightBooks::Play)]
+    night_books: NightBooks,
+
+    /// Max volume in night mode (1 to 10)
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u8).range(1..=10))]
+    night_volume: u8,
+
+    /// Volume level at the first start (then the last volume is restored)
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u8).range(1..=10))]
+    volume: u8,
+
+    /// Lowest volume level (1 to 10)
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..=10))]
+    volume_min: u8,
+
+    /// Highest volume level (1 to 10)
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u8).range(1..=10))]
+    volume_max: u8,
+
+    /// Levels changed by UP and DOWN
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=9))]
+    volume_step: u8,
+
+    /// Backlight when idle (no button pressed and no audio playing)
+    #[arg(long, value_enum, default_value_t = IdleScreen::Off)]
+    idle_screen: IdleScreen,
+
+    /// Seconds before the idle backlight (0 keeps the screen on)
+    #[arg(long, default_value_t = 60)]
+    idle_screen_delay: u64,
+
+    /// Minutes before the poweroff when idle (0 never powers off)
+    #[arg(long, default_value_t = 15)]
+    idle_poweroff: u64,
+
+    /// Key of a Lunii device: the AES key and IV of a v3 (32 bytes), the
+    /// XXTEA key of a v2 (16 bytes) or the .md file of a v2
+    #[arg(short, long, global = true)]
+    device_key: Option<PathBuf>,
+
     /// The path to the books directory
-    books: std::path::PathBuf,
+    #[arg(required = true)]
+    books: Option<PathBuf>,
+}
+
+/// Limit the volume and filter the books according to the night mode
+fn night_apply(
+    night: &Night,
+    books: &mut Books,
+    player: &mut Player,
+    night_books: NightBooks,
+    night_volume: u8,
+) {
+    if night.is_enabled() {
+        player.set_volume_max(Some(night_volume as usize));
+    } else {
+        player.set_volume_max(None);
+    }
+    books.set_night_only(night.is_enabled() && night_books == NightBooks::Skip);
+}
+
+/// List the books of a directory, or the book itself
+fn book_paths(path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    if Source::detect(path).is_some() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if Source::detect(&path).is_some() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn check(path: &Path, device_key: Option<&Cipher>) -> Result<u8, Box<dyn Error>> {
+    let mut status_code = 0;
+
+    for path in book_paths(path)? {
+        let Some(source) = Source::detect(&path) else {
+            continue;
+        };
+
+        match Book::from_source(source, device_key) {
+            Ok(book) => {
+                let issues = book.check();
+                if issues.is_empty() {
+                    println!("{:?}: ok", path);
+                }
+                Books::report(&path, &issues);
+                if issues
+                    .iter()
+                    .any(|issue| issue.severity() == Severity::Error)
+                {
+                    status_code = 1;
+                }
+            }
+            Err(e) => {
+                eprintln!("{:?}: error: {}", path, e);
+                status_code = 1;
+            }
+        }
+    }
+
+    Ok(status_code)
+}
+
+fn info(
+    path: &Path,
+    json: bool,
+    duration: bool,
+    device_key: Option<&Cipher>,
+) -> Result<u8, Box<dyn Error>> {
+    let mut infos = Vec::new();
+
+    for path in book_paths(path)? {
+        let Some(source) = Source::detect(&path) else {
+            continue;
+        };
+
+        match Book::from_source(source, device_key) {
+            Ok(book) => {
+                let mut info = book.info();
+                if info.thumbnail.is_none() {
+                    info.thumbnail = book
+                        .thumbnail_cache(&Books::cache_dir_of(&path))
+                        .ok()
+                        .flatten();
+                }
+                if duration {
+                    info.duration = Some(book.duration().as_secs());
+                }
+                infos.push(info);
+            }
+            Err(e) => eprintln!("{:?}: error: {}", path, e),
+        }
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&infos)?);
+        return Ok(0);
+    }
+
+    for info in infos {
+        println!("{}: {}", info.id, info.title.as_deref().unwrap_or("-"));
+        if let Some(ref uuid) = info.uuid {
+            println!("  uuid: {uuid}");
+        }
+        println!("  format: {} (version {})", info.format, info.version);
+        println!(
+            "  nodes: {} stages, {} actions",
+            info.stage_nodes, info.action_nodes
+        );
+        println!("  night mode: {}", info.night_mode);
+        if let Some(duration) = info.duration {
+            println!("  duration: {}:{:02}", duration / 60, duration % 60);
+        }
+    }
+
+    Ok(0)
+}
+
+fn graph(
+    path: &Path,
+    format: GraphFormat,
+    device_key: Option<&Cipher>,
+) -> Result<u8, Box<dyn Error>> {
+    let source = Source::detect(path).ok_or("Not a book")?;
+    let book = Book::from_source(source, device_key)?;
+    print!("{}", book.graph(format));
+    Ok(0)
+}
+
+fn normalize(path: &Path, device_key: Option<&Cipher>) -> Result<u8, Box<dyn Error>> {
+    let count = Books::normalize(path, device_key)?;
+    println!("{count} book(s) analyzed");
+    Ok(0)
+}
+
+fn devices() -> Result<u8, Box<dyn Error>> {
+    for name in Output::devices()? {
+        println!("{name}");
+    }
+    Ok(0)
+}
+
+fn convert(
+    path: &Path,
+    dest: &Path,
+    png: bool,
+    ogg: bool,
+    fs: bool,
+    device_key: Option<&Cipher>,
+) -> Result<u8, Box<dyn Error>> {
+    let source = Source::detect(path).ok_or("Not a book")?;
+    let book = Book::from_source(source, device_key)?;
+    if fs {
+        book.to_fs_directory(dest)?;
+    } else {
+        book.to_archive_dir(dest, png, ogg)?;
+    }
+    Ok(0)
 }
 
 fn run() -> Result<u8, Box<dyn Error>> {
     let args = Cli::parse();
+
+    let device_key = match args.device_key {
+        Some(ref path) => Some(Cipher::from_device_key_file(path)?),
+        None => None,
+    };
+
+    match args.command {
+        Some(Command::Check { path }) => return check(&path, device_key.as_ref()),
+        Some(Command::Info {
+            json,
+            duration,
+            path,
+        }) => return info(&path, json, duration, device_key.as_ref()),
+        Some(Command::Graph { format, path }) => {
+            return graph(&path, format, device_key.as_ref());
+        }
+        Some(Command::Normalize { path }) => return normalize(&path, device_key.as_ref()),
+        Some(Command::Devices) => return devices(),
+        Some(Command::Convert {
+            png,
+            ogg,
+            fs,
+            path,
+            dest,
+        }) => {
+            return convert(&path, &dest, png, ogg, fs, device_key.as_ref());
+        }
+        None => (),
+    }
+
     let (tx, rx) = channel::<(KeyCode, Option<Status>, bool)>();
 
     //// Listen for signals ////////////////////////////////////////////////////
@@ -187,16 +609,82 @@ fn run() -> Result<u8, Box<dyn Error>> {
         }
     });
 
-    let path = args.books;
+    let path = args.books.ok_or("Missing books directory")?;
+
+    //// Listen for the books changes //////////////////////////////////////////
+    let changes: Arc<Mutex<HashSet<String>>> = Arc::default();
+    let watched = path.clone();
+    let tx_books = tx.clone();
+    let changes_books = changes.clone();
+    thread::spawn(move || -> Option<()> {
+        let mut watcher = Watcher::new(&watched).ok()?;
+        loop {
+            if let Ok(names) = watcher.listen() {
+                println!("Books changed: {:?}", names);
+                changes_books.lock().ok()?.extend(names);
+                let _ = tx_books.send((KeyCode::KEY_REFRESH, None, false));
+            }
+        }
+    });
+
+    //// Analyze the loudness of the new books ////////////////////////////////
+    if args.normalize {
+        let analyzed = path.clone();
+        thread::spawn(move || {
+            if let Err(e) = Books::normalize(&analyzed, device_key.as_ref()) {
+                eprintln!("Cannot analyze the loudness: {}", e);
+            }
+        });
+    }
+
     let fb = args.fb;
     let services = Services::new()?;
-    let mut books = Books::from_dir(&path)?;
+    let mut books = Books::from_dir(&path, args.resume, args.order, device_key)?;
+    books.set_shuffle(args.shuffle);
     let mut screen = Screen::new(fb.as_path())?;
-    let mut player = Player::new()?;
+    let volume = Volume::new(
+        books.volume().unwrap_or(args.volume as usize),
+        args.volume_min as usize,
+        args.volume_max as usize,
+        args.volume_step as usize,
+    );
+    let output = Output {
+        device: args.audio_device.clone(),
+        card: args.mixer_card.clone(),
+        control: args.mixer.clone(),
+    };
+    let tx_output = tx.clone();
+    let mut player = Player::new(volume, output, move || {
+        let _ = tx_output.send((KeyCode::KEY_SOUND, None, true));
+    });
+    let mut recovered: Option<Duration> = None;
+    /* The queued audio is only taken at the end of the current audio */
+    let mut ended = false;
+    let cache_dir = books.cache_dir();
     let mut next = Next::Normal;
     let mut timeout: Option<Timeout> = None;
     let mut settings = false;
     let mut status_code = 0;
+    let mut night = Night::new(args.night);
+    let (night_books, night_volume) = (args.night_books, args.night_volume);
+    let tx_sleep = tx.clone();
+    let mut parental = Parental::load(&path);
+    player.set_volume_parental(parental.max_volume());
+    let mut parental_timeout: Option<Timeout> = None;
+    let mut sleep = Sleep::new(move || {
+        let _ = tx_sleep.send((KeyCode::KEY_SLEEP, None, true));
+    });
+    let (tx_idle_screen, tx_idle_poweroff) = (tx.clone(), tx.clone());
+    let mut idle = Idle::new(
+        Duration::from_secs(args.idle_screen_delay),
+        Duration::from_secs(args.idle_poweroff * 60),
+        move || {
+            let _ = tx_idle_screen.send((KeyCode::KEY_SCREENSAVER, None, true));
+        },
+        move || {
+            let _ = tx_idle_poweroff.send((KeyCode::KEY_SUSPEND, None, true));
+        },
+    );
 
     let mut assets_dir = env::current_exe()?;
     assets_dir.pop();
@@ -204,12 +692,32 @@ fn run() -> Result<u8, Box<dyn Error>> {
     assets_dir = assets_dir.join("share/contelia/assets");
 
     while next != Next::Shutdown {
+        if next == Next::Normal && night.update_now() {
+            night_apply(&night, &mut books, &mut player, night_books, night_volume);
+        }
+
+        let audio_offset = recovered
+            .take()
+            .unwrap_or_else(|| books.take_audio_offset());
+        let playlist = books.is_playlist();
         let Some(book) = books.get() else {
             return Err("No book available".into());
         };
         let Some(state) = book.stage_get() else {
             return Err("Invalid book state".into());
         };
+        let book_id = book.id().to_string();
+
+        /* The parental limits are checked before to play anything */
+        if (next == Next::Normal || next == Next::Audio)
+            && let Some(limit) = parental.limit(&book_id, state.square_one)
+        {
+            println!("Parental limit: {limit:?}");
+            player.stop();
+            book.stage_reset();
+            next = Next::TimesUp;
+            continue;
+        }
 
         if next != Next::Timeout {
             if let Some(ref mut timeout) = timeout {
@@ -222,13 +730,17 @@ fn run() -> Result<u8, Box<dyn Error>> {
         println!("{next:?}");
 
         if next == Next::Normal || next == Next::Image {
+            idle.wake();
+            idle.activity();
+
             match state.image {
-                Some(ref image) => {
+                /* The screen stays off after the cover in night mode */
+                Some(ref image) if !night.is_enabled() || state.square_one => {
                     let (mut image, format) = book.images_file_get(&image)?;
                     screen.draw(&mut image, format)?;
                     screen.on()?;
                 }
-                None => {
+                _ => {
                     screen.off()?;
                     screen.clear()?;
                 }
@@ -237,28 +749,70 @@ fn run() -> Result<u8, Box<dyn Error>> {
 
         if next == Next::Normal || next == Next::Audio {
             match state.audio {
+                /* Already playing after the previous audio */
+                Some(ref audio) if player.take_queued(&format!("{book_id}/{audio}"), ended) => {}
                 Some(ref audio) => {
+                    if args.normalize {
+                        player.set_gain(book.loudness_gain(audio, &cache_dir));
+                    }
                     let audio = book.audio_file_get(&audio)?;
                     let tx_play = tx.clone();
-                    player.play(audio, move || {
-                        let code = if state.control_settings.ok || state.control_settings.autoplay {
-                            KeyCode::BTN_START
-                        } else if state.control_settings.home {
-                            KeyCode::BTN_SELECT
-                        } else {
-                            return;
-                        };
-                        let _ = tx_play.send((code, None, true));
-                    })?;
+                    let code = eos_code(&state.control_settings);
+                    let played = player.play_from(audio, audio_offset, move || {
+                        if let Some(code) = code {
+                            let _ = tx_play.send((code, None, true));
+                        }
+                    });
+                    if let Err(e) = played {
+                        eprintln!("Cannot play the audio: {}", e);
+                    }
+                }
+                None => player.unqueue(),
+            }
+
+            /* Open the audio of the next stage now for a gapless playback,
+             * the stories chained by a playlist or by the night mode are
+             * not following the OK transition.
+             */
+            if state.audio.is_some()
+                && state.control_settings.autoplay
+                && !(state.is_story() && (playlist || night.is_enabled()))
+                && let Some(stage) = book.ok_peek()
+                && let Some(ref audio) = stage.audio
+            {
+                if args.normalize {
+                    player.set_gain(book.loudness_gain(audio, &cache_dir));
+                }
+                let crossfade = match stage.is_story() {
+                    true => Duration::ZERO,
+                    false => Duration::from_millis(args.crossfade),
+                };
+                let key = format!("{book_id}/{audio}");
+                let tx_play = tx.clone();
+                let code = eos_code(&stage.control_settings);
+                let queued = match book.audio_file_get(audio) {
+                    Ok(audio) => player.queue(key, audio, crossfade, move || {
+                        if let Some(code) = code {
+                            let _ = tx_play.send((code, None, true));
+                        }
+                    }),
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = queued {
+                    eprintln!("Cannot queue the next audio: {}", e);
                 }
-                None => {}
             }
         }
 
         if next == Next::Settings && settings {
             if services.stop().is_ok() {
                 settings = false;
-                books.reload();
+                /* The limits can be changed by the admin interface */
+                parental.reload();
+                player.set_volume_parental(parental.max_volume());
+                let names = changes.lock().map(|mut names| std::mem::take(&mut *names));
+                books.refresh(&names.unwrap_or_default());
+                books.resume_audio();
                 next = Next::Normal;
                 continue; /* Restore image and/or audio */
             }
@@ -267,6 +821,11 @@ fn run() -> Result<u8, Box<dyn Error>> {
         if next == Next::Settings {
             if services.start().is_ok() {
                 settings = true;
+
+                /* Keep the audio offset in order to continue after the settings */
+                if let Err(e) = books.save(player.position()) {
+                    eprintln!("Cannot save the state: {}", e);
+                }
                 player.stop();
 
                 let image = assets_dir.join("settings.png");
@@ -293,6 +852,92 @@ fn run() -> Result<u8, Box<dyn Error>> {
             }));
         }
 
+        if next == Next::Night {
+            night.toggle();
+            night_apply(&night, &mut books, &mut player, night_books, night_volume);
+
+            let image = if night.is_enabled() {
+                assets_dir.join("night.png")
+            } else {
+                assets_dir.join("day.png")
+            };
+            let path = Path::new(&image);
+            println!("night image: {}", path.display());
+            let mut file = FileReader::Plain(File::open(path)?);
+            screen.draw(&mut file, image::ImageFormat::Png)?;
+            screen.on()?;
+
+            let tx_timeout = tx.clone();
+            timeout = Some(Timeout::set(Duration::from_millis(800), move || {
+                let _ = tx_timeout.send((KeyCode::KEY_TIME, None, true));
+            }));
+        }
+
+        if next == Next::Favorite {
+            let image = match books.toggle_favorite() {
+                Ok(true) => assets_dir.join("favorite.png"),
+                Ok(false) => assets_dir.join("unfavorite.png"),
+                Err(err) => {
+                    eprintln!("Cannot save the favorites: {err}");
+                    assets_dir.join("unfavorite.png")
+                }
+            };
+            let path = Path::new(&image);
+            println!("favorite image: {}", path.display());
+            let mut file = FileReader::Plain(File::open(path)?);
+            screen.draw(&mut file, image::ImageFormat::Png)?;
+            screen.on()?;
+
+            let tx_timeout = tx.clone();
+            timeout = Some(Timeout::set(Duration::from_millis(800), move || {
+                let _ = tx_timeout.send((KeyCode::KEY_TIME, None, true));
+            }));
+        }
+
+        if next == Next::Shuffle {
+            let image = if books.toggle_shuffle() {
+                assets_dir.join("shuffle.png")
+            } else {
+                assets_dir.join("sequence.png")
+            };
+            let path = Path::new(&image);
+            println!("shuffle image: {}", path.display());
+            let mut file = FileReader::Plain(File::open(path)?);
+            screen.draw(&mut file, image::ImageFormat::Png)?;
+            screen.on()?;
+
+            let tx_timeout = tx.clone();
+            timeout = Some(Timeout::set(Duration::from_millis(800), move || {
+                let _ = tx_timeout.send((KeyCode::KEY_TIME, None, true));
+            }));
+        }
+
+        if next == Next::Sleep {
+            let minutes = sleep.cycle();
+            player.set_fade(1.0);
+
+            let image = assets_dir.join(format!("sleep{:0>2}.png", minutes));
+            let path = Path::new(&image);
+            println!("sleep image: {}", path.display());
+            let mut file = FileReader::Plain(File::open(path)?);
+            screen.draw(&mut file, image::ImageFormat::Png)?;
+            screen.on()?;
+
+            let tx_timeout = tx.clone();
+            timeout = Some(Timeout::set(Duration::from_millis(800), move || {
+                let _ = tx_timeout.send((KeyCode::KEY_TIME, None, true));
+            }));
+        }
+
+        if next == Next::TimesUp {
+            let image = assets_dir.join("timesup.png");
+            let path = Path::new(&image);
+            println!("times up image: {}", path.display());
+            let mut file = FileReader::Plain(File::open(path)?);
+            screen.draw(&mut file, image::ImageFormat::Png)?;
+            screen.on()?;
+        }
+
         if next == Next::Pause || next == Next::Play {
             let image = if next == Next::Play {
                 assets_dir.join("play.png")
@@ -311,25 +956,193 @@ fn run() -> Result<u8, Box<dyn Error>> {
             }));
         }
 
+        if next == Next::Progress {
+            let image = match player.is_paused() {
+                true => assets_dir.join("pause.png"),
+                false => assets_dir.join("play.png"),
+            };
+            let path = Path::new(&image);
+            println!("progress image: {}", path.display());
+            let mut file = FileReader::Plain(File::open(path)?);
+            screen.draw(&mut file, image::ImageFormat::Png)?;
+            screen.progress(player.progress().unwrap_or_default())?;
+            screen.on()?;
+
+            let tx_timeout = tx.clone();
+            timeout = Some(Timeout::set(Duration::from_millis(800), move || {
+                let _ = tx_timeout.send((KeyCode::KEY_TIME, None, true));
+            }));
+        }
+
+        /* Count the listening time and stop the audio at the next limit */
+        parental.update(player.is_playing().then_some(book_id.as_str()));
+        if let Some(ref mut timeout) = parental_timeout {
+            timeout.clear();
+        }
+        parental_timeout = None;
+        if player.is_playing()
+            && let Some(remaining) = parental.remaining(&book_id, state.square_one)
+        {
+            let tx_limit = tx.clone();
+            parental_timeout = Some(Timeout::set(remaining, move || {
+                let _ = tx_limit.send((KeyCode::KEY_STOP, None, true));
+            }));
+        }
+
         next = Next::Normal;
         match rx.recv() {
             Ok((code, status, eos)) => {
-                if let Some(status) = status {
+                ended = eos;
+                parental.update(player.is_playing().then_some(book_id.as_str()));
+
+                // A button wakes the screen without action
+                if status.is_some() && code != KeyCode::KEY_POWER {
+                    idle.activity();
+                    if idle.wake() {
+                        next = Next::Image; // Restore screen
+                        continue;
+                    }
+                }
+
+                if let Some(ref status) = status {
                     if status.dpad_down && status.select && status.start {
                         next = Next::Settings;
                         continue;
                     }
                 }
 
+                // A long press on the wheel seeks in a story, elsewhere it's
+                // a normal press (only once). On the cover, a long press on OK
+                // toggles the favorite and a long press on HOME toggles the
+                // shuffle, elsewhere they're normal presses.
+                let seek = state.is_story() && !state.control_settings.wheel;
+                let long_press = status.as_ref().map_or(0, |status| status.long_press);
+                let code = match code {
+                    KeyCode::KEY_REWIND if !seek && long_press == 1 => KeyCode::BTN_DPAD_LEFT,
+                    KeyCode::KEY_FASTFORWARD if !seek && long_press == 1 => KeyCode::BTN_DPAD_RIGHT,
+                    KeyCode::KEY_FAVORITES if !state.square_one => KeyCode::BTN_START,
+                    KeyCode::KEY_SHUFFLE if !state.square_one => KeyCode::BTN_SELECT,
+                    code => code,
+                };
+
                 if code == KeyCode::KEY_END {
                     next = Next::Shutdown; // Clean shutdown
                 } else if code == KeyCode::KEY_POWER {
                     next = Next::Shutdown;
                     status_code = 42; // Poweroff
+                } else if code == KeyCode::KEY_SLEEP {
+                    // Fade out then poweroff like the power button
+                    match sleep.tick() {
+                        Some(fade) => {
+                            player.set_fade(fade);
+                            next = Next::Timeout;
+                        }
+                        None => {
+                            next = Next::Shutdown;
+                            status_code = 42;
+                        }
+                    }
+                } else if code == KeyCode::KEY_SCREENSAVER {
+                    // Only if nothing is playing and waiting on a button
+                    if settings || player.is_playing() || state.control_settings.autoplay {
+                        idle.screen_timer();
+                    } else {
+                        match args.idle_screen {
+                            IdleScreen::Dim => screen.dim()?,
+                            IdleScreen::Off => screen.off()?,
+                        }
+                        idle.sleep();
+                    }
+                    next = Next::Timeout;
+                } else if code == KeyCode::KEY_SUSPEND {
+                    if settings || player.is_playing() {
+                        idle.poweroff_timer();
+                        next = Next::Timeout;
+                    } else {
+                        next = Next::Shutdown;
+                        status_code = 42; // Poweroff
+                    }
+                } else if code == KeyCode::KEY_SOUND && settings {
+                    // The audio device is lost, only open the new one (the
+                    // audio continues after the settings)
+                    player.recover();
+                    next = Next::None;
                 } else if settings == true {
                     next = Next::None;
+                } else if code == KeyCode::KEY_REFRESH {
+                    parental.reload();
+                    player.set_volume_parental(parental.max_volume());
+                    let before = books
+                        .get()
+                        .map(|book| (book.id().to_string(), book.position()));
+                    let names = changes.lock().map(|mut names| std::mem::take(&mut *names));
+                    books.refresh(&names.unwrap_or_default());
+                    let after = books
+                        .get()
+                        .map(|book| (book.id().to_string(), book.position()));
+
+                    // Nothing to restore if the selected book is the same
+                    next = if before == after {
+                        Next::Timeout
+                    } else {
+                        Next::Normal
+                    };
+                } else if code == KeyCode::KEY_SOUND {
+                    // The audio device is lost, continue on the new one
+                    recovered = player.recover();
+                    next = match recovered {
+                        Some(_) => Next::Audio,
+                        None => Next::Timeout,
+                    };
+                } else if (code == KeyCode::KEY_REWIND || code == KeyCode::KEY_FASTFORWARD) && seek
+                {
+                    // Long press on the wheel, seek in the story
+                    let step = match long_press > SEEK_FAST_AFTER {
+                        true => SEEK_FAST_STEP,
+                        false => SEEK_STEP,
+                    };
+                    let step = match code == KeyCode::KEY_REWIND {
+                        true => -step,
+                        false => step,
+                    };
+                    next = match player.seek_relative(step) {
+                        Ok(position) => {
+                            println!("seek to {:?}", position);
+                            Next::Progress
+                        }
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            Next::Timeout
+                        }
+                    };
+                } else if code == KeyCode::KEY_STOP {
+                    // Time's up while playing
+                    next = match parental.limit(&book_id, state.square_one) {
+                        Some(_) => Next::Normal,
+                        None => Next::Timeout,
+                    };
                 } else if code == KeyCode::KEY_TIME {
                     next = Next::Image; // Restore screen
+                } else if eos && state.is_story() && books.is_playlist() {
+                    // Play the next item, silence after the last one
+                    next = match books.playlist_next() {
+                        Some(_) => Next::Normal,
+                        None => Next::None,
+                    };
+                } else if eos
+                    && night.is_enabled()
+                    && state.is_story()
+                    && let Some(book) = books.get()
+                    && book.night_mode_available()
+                {
+                    // Chain the stories, silence after the last one
+                    next = match book.night_next() {
+                        Some(_) => Next::Normal,
+                        None => {
+                            book.stage_reset();
+                            Next::None
+                        }
+                    };
                 } else if eos && !state.control_settings.autoplay {
                     // Ignore EOS when autoplay is disabled
                     next = if timeout.is_none() {
@@ -338,11 +1151,36 @@ fn run() -> Result<u8, Box<dyn Error>> {
                         Next::Timeout
                     };
                 } else {
-                    next = process_event(&mut books, &mut player, &state, code, eos);
+                    next = process_event(
+                        &mut books,
+                        &mut player,
+                        &state,
+                        code,
+                        status.as_ref(),
+                        eos,
+                        args.back,
+                    );
                 }
             }
             Err(_) => (),
         };
+
+        let audio_offset = match next {
+            Next::Normal => Some(Duration::ZERO),
+            Next::Shutdown => Some(player.position()),
+            _ => None,
+        };
+        books.set_volume(player.get_volume());
+        if let Some(audio_offset) = audio_offset
+            && let Err(e) = books.save(audio_offset)
+        {
+            eprintln!("Cannot save the state: {}", e);
+        }
+        if audio_offset.is_some()
+            && let Err(e) = parental.save()
+        {
+            eprintln!("Cannot save the listening time: {}", e);
+        }
     }
 
     if next == Next::Shutdown {
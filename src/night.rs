@@ -0,0 +1,207 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use chrono::{Local, NaiveTime, TimeDelta};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, time::Duration};
+
+/// Books without night mode, when the night mode is enabled
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum NightBooks {
+    /// Play them normally (the stories are not chained)
+    #[default]
+    Play,
+    /// Skip them in the library
+    Skip,
+}
+
+/// Time range of the night, like 19:30-07:00
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Schedule {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            /* Over midnight */
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// Time before the next start of the range
+    pub fn until_start(&self, time: NaiveTime) -> Duration {
+        let mut delta = self.start - time;
+        if delta < TimeDelta::zero() {
+            delta += TimeDelta::days(1);
+        }
+        delta.to_std().unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> Self {
+        schedule.to_string()
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or("Expected a time range like 19:30-07:00")?;
+        let parse = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M").map_err(|e| format!("{time}: {e}"))
+        };
+
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+/// Night mode, toggled with a button or by the schedule
+#[derive(Debug, Default)]
+pub struct Night {
+    enabled: bool,
+    schedule: Option<Schedule>,
+    scheduled: Option<bool>,
+}
+
+impl Night {
+    pub fn new(schedule: Option<Schedule>) -> Self {
+        Self {
+            schedule,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+    }
+
+    /// Follow the schedule, a manual toggle is kept until the next start
+    /// or end of the night. It returns true if the mode has changed.
+    pub fn update(&mut self, time: NaiveTime) -> bool {
+        let Some(schedule) = self.schedule else {
+            return false;
+        };
+
+        let scheduled = schedule.contains(time);
+        if self.scheduled == Some(scheduled) {
+            return false;
+        }
+
+        self.scheduled = Some(scheduled);
+        let changed = self.enabled != scheduled;
+        self.enabled = scheduled;
+        changed
+    }
+
+    pub fn update_now(&mut self) -> bool {
+        self.update(Local::now().time())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn schedule() {
+        let schedule: Schedule = "19:30-07:00".parse().expect("invalid schedule");
+        assert!(schedule.contains(time("19:30")));
+        assert!(schedule.contains(time("23:59")));
+        assert!(schedule.contains(time("00:00")));
+        assert!(!schedule.contains(time("07:00")));
+        assert!(!schedule.contains(time("12:00")));
+
+        let schedule: Schedule = "13:00-15:00".parse().expect("invalid schedule");
+        assert!(schedule.contains(time("14:00")));
+        assert!(!schedule.contains(time("15:00")));
+        assert!(!schedule.contains(time("20:00")));
+
+        assert_eq!(schedule.to_string(), "13:00-15:00");
+        assert_eq!(
+            schedule.until_start(time("12:30")),
+            Duration::from_secs(30 * 60)
+        );
+        assert_eq!(
+            schedule.until_start(time("14:00")),
+            Duration::from_secs(23 * 3600)
+        );
+
+        assert!("19:30".parse::<Schedule>().is_err());
+        assert!("19:30-25:00".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn update() {
+        let mut night = Night::new(None);
+        assert!(!night.update(time("22:00")));
+        night.toggle();
+        assert!(night.is_enabled());
+
+        let mut night = Night::new(Some("19:30-07:00".parse().unwrap()));
+        assert!(!night.update(time("12:00")));
+        assert!(night.update(time("20:00")));
+        assert!(night.is_enabled());
+
+        /* The manual toggle is kept until the end of the night */
+        night.toggle();
+        assert!(!night.update(time("23:00")));
+        assert!(!night.is_enabled());
+        assert!(!night.update(time("07:30")));
+        assert!(night.update(time("19:45")));
+        assert!(night.is_enabled());
+    }
+}
@@ -0,0 +1,208 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::Result;
+use clap::ValueEnum;
+use std::{
+    cmp::Reverse,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    time::SystemTime,
+};
+
+const ORDER_FILE: &str = "order";
+const PINNED_FILE: &str = "pinned";
+const FAVORITES_FILE: &str = "favorites";
+
+/// How the books are sorted in the library
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Order {
+    /// By folder name
+    #[default]
+    Name,
+    /// By title (the folder name is used when the title is missing)
+    Title,
+    /// By upload date (the newest first)
+    Date,
+    /// Like the `order` file (one book per line), the other books follow
+    Manual,
+}
+
+/// Manual order, pinned and favorite books. They are stored in the books
+/// directory as lists of books identifiers (one per line).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Ordering {
+    pub manual: Vec<String>,
+    pub pinned: Vec<String>,
+    pub favorites: Vec<String>,
+}
+
+fn list_read(path: &Path) -> Vec<String> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl Ordering {
+    /// Load the lists, the missing files are empty lists
+    pub fn load(dir: &Path) -> Self {
+        Self {
+            manual: list_read(&dir.join(ORDER_FILE)),
+            pinned: list_read(&dir.join(PINNED_FILE)),
+            favorites: list_read(&dir.join(FAVORITES_FILE)),
+        }
+    }
+
+    /// Write the favorites like the state (temporary file then rename)
+    pub fn save_favorites(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(FAVORITES_FILE);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for id in &self.favorites {
+            writeln!(file, "{id}")?;
+        }
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    pub fn toggle_favorite(&mut self, id: &str) {
+        match self.favorites.iter().position(|favorite| favorite == id) {
+            Some(index) => {
+                self.favorites.remove(index);
+            }
+            None => self.favorites.push(id.to_string()),
+        }
+    }
+
+    /// Sort the books, the pinned books come first (in the order of the
+    /// file), then the favorites and the other books. The key gives the
+    /// identifier, the path and the title of an item.
+    pub fn sort<T>(
+        &self,
+        items: &mut [T],
+        order: Order,
+        key: impl Fn(&T) -> (&str, &Path, Option<&str>),
+    ) {
+        items.sort_by_cached_key(|item| {
+            let (id, path, title) = key(item);
+            let pinned = self.pinned.iter().position(|pinned| pinned == id);
+            let group = match pinned {
+                Some(_) => 0,
+                None if self.favorites.iter().any(|favorite| favorite == id) => 1,
+                None => 2,
+            };
+
+            let date = (order == Order::Date).then(|| {
+                let modified = fs::metadata(path).and_then(|metadata| metadata.modified());
+                Reverse(modified.unwrap_or(SystemTime::UNIX_EPOCH))
+            });
+            let manual = match order {
+                Order::Manual => self
+                    .manual
+                    .iter()
+                    .position(|manual| manual == id)
+                    .unwrap_or(usize::MAX),
+                _ => 0,
+            };
+            let name = match order {
+                Order::Title => title.unwrap_or(id),
+                _ => id,
+            };
+
+            (
+                group,
+                pinned.unwrap_or(0),
+                date,
+                manual,
+                name.to_lowercase(),
+                id.to_string(),
+            )
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    type Item = (&'static str, PathBuf, &'static str);
+
+    fn ids(items: &[Item]) -> Vec<&str> {
+        items.iter().map(|item| item.0).collect()
+    }
+
+    fn sort_items(ordering: &Ordering, items: &mut [Item], order: Order) {
+        ordering.sort(items, order, |item| (item.0, &item.1, Some(item.2)));
+    }
+
+    #[test]
+    fn sort() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let mut books = Vec::new();
+        for (i, (id, title)) in [("b", "Zebra"), ("a", "yak"), ("c", "Xylophone")]
+            .into_iter()
+            .enumerate()
+        {
+            let path = dir.path().join(id);
+            fs::create_dir(&path).unwrap();
+            let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1000 * i as u64);
+            File::open(&path)
+                .and_then(|file| file.set_modified(modified))
+                .expect("cannot set the date");
+            books.push((id, path, title));
+        }
+
+        let ordering = Ordering::load(dir.path());
+        assert_eq!(ordering, Ordering::default());
+
+        sort_items(&ordering, &mut books, Order::Name);
+        assert_eq!(ids(&books), ["a", "b", "c"]);
+        sort_items(&ordering, &mut books, Order::Title);
+        assert_eq!(ids(&books), ["c", "a", "b"]);
+        sort_items(&ordering, &mut books, Order::Date);
+        assert_eq!(ids(&books), ["c", "a", "b"]);
+
+        fs::write(dir.path().join(ORDER_FILE), "c\n\nb\nmissing\n").unwrap();
+        let mut ordering = Ordering::load(dir.path());
+        sort_items(&ordering, &mut books, Order::Manual);
+        assert_eq!(ids(&books), ["c", "b", "a"]);
+
+        /* The pinned books, then the favorites */
+        fs::write(dir.path().join(PINNED_FILE), "b\n").unwrap();
+        ordering.toggle_favorite("c");
+        ordering
+            .save_favorites(dir.path())
+            .expect("cannot save favorites");
+        let mut ordering = Ordering::load(dir.path());
+        assert_eq!(ordering.favorites, ["c"]);
+        sort_items(&ordering, &mut books, Order::Name);
+        assert_eq!(ids(&books), ["b", "c", "a"]);
+
+        ordering.toggle_favorite("c");
+        sort_items(&ordering, &mut books, Order::Name);
+        assert_eq!(ids(&books), ["b", "a", "c"]);
+    }
+}
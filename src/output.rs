@@ -0,0 +1,122 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use alsa::{
+    Round,
+    mixer::{MilliBel, Mixer, SelemId},
+};
+use anyhow::{Context, Result};
+use rodio::{
+    DeviceTrait, OutputStream, OutputStreamBuilder,
+    cpal::{self, traits::HostTrait},
+};
+
+/// Where the audio is played, the default device with a software volume
+/// when nothing is set
+#[derive(Clone, Debug, Default)]
+pub struct Output {
+    /// Name of the device (see `Output::devices`)
+    pub device: Option<String>,
+    /// ALSA card of the mixer
+    pub card: String,
+    /// ALSA mixer control used for the volume (like "PCM")
+    pub control: Option<String>,
+}
+
+/// Value of a mixer for a factor of the volume, in the range of the
+/// control (in mB)
+fn mixer_db(gain: f32, (min, max): (MilliBel, MilliBel)) -> MilliBel {
+    if gain <= 0.0 {
+        return min;
+    }
+    MilliBel::from_db(20.0 * gain.log10()).clamp(min, max)
+}
+
+impl Output {
+    /// Names of the output devices
+    pub fn devices() -> Result<Vec<String>> {
+        Ok(cpal::default_host()
+            .output_devices()?
+            .filter_map(|device| device.name().ok())
+            .collect())
+    }
+
+    /// Open the stream of the device, the default device is used if the
+    /// device is not found. The callback is called on errors (like a
+    /// device which is unplugged).
+    pub fn open<F>(&self, on_error: F) -> Result<OutputStream>
+    where
+        F: FnMut(cpal::StreamError) + Send + 'static,
+    {
+        let device = self.device.as_ref().and_then(|name| {
+            let device = cpal::default_host()
+                .output_devices()
+                .ok()?
+                .find(|device| device.name().is_ok_and(|n| &n == name));
+            if device.is_none() {
+                eprintln!("Audio device {:?} not found, use the default", name);
+            }
+            device
+        });
+
+        let builder = match device {
+            Some(device) => OutputStreamBuilder::from_device(device)?,
+            None => OutputStreamBuilder::from_default_device()?,
+        };
+        let mut stream = builder.with_error_callback(on_error).open_stream()?;
+        stream.log_on_drop(false);
+        Ok(stream)
+    }
+
+    /// Set the volume with the mixer, false if there is no mixer
+    pub fn mixer_set(&self, gain: f32) -> Result<bool> {
+        let Some(ref control) = self.control else {
+            return Ok(false);
+        };
+
+        let mixer = Mixer::new(&self.card, false)?;
+        let selem = mixer
+            .find_selem(&SelemId::new(control, 0))
+            .with_context(|| format!("Mixer control {control:?} not found"))?;
+
+        let (min, max) = selem.get_playback_db_range();
+        if min < max {
+            selem.set_playback_db_all(mixer_db(gain, (min, max)), Round::Floor)?;
+        } else {
+            /* Without dB range, the raw volume is linear */
+            let (min, max) = selem.get_playback_volume_range();
+            let value = min + ((max - min) as f32 * gain.clamp(0.0, 1.0)).round() as i64;
+            selem.set_playback_volume_all(value)?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mixer() {
+        let range = (MilliBel(-5000), MilliBel(0));
+        assert_eq!(mixer_db(1.0, range), MilliBel(0));
+        assert_eq!(mixer_db(0.5, range), MilliBel(-602));
+        assert_eq!(mixer_db(0.001, range), MilliBel(-5000));
+        assert_eq!(mixer_db(0.0, range), MilliBel(-5000));
+        assert_eq!(mixer_db(2.0, range), MilliBel(0));
+    }
+}
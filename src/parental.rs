@@ -0,0 +1,321 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::Result;
+use chrono::{Local, NaiveTime};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::night::Schedule;
+
+/// Edited by the admin interface
+const LIMITS_FILE: &str = "parental.json";
+const USAGE_FILE: &str = ".contelia-usage.json";
+
+/// Parental settings, the missing limits are disabled
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Limits {
+    /// Listening time per day (in minutes)
+    #[serde(default)]
+    pub daily_quota: Option<u64>,
+    /// Listening time per book and per day (in minutes)
+    #[serde(default)]
+    pub book_quota: Option<u64>,
+    /// No listening in this time range, like 20:00-07:00
+    #[serde(default)]
+    pub curfew: Option<Schedule>,
+    /// Highest volume level (1 to 10)
+    #[serde(default)]
+    pub max_volume: Option<usize>,
+}
+
+/// Listening time of the day
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Usage {
+    date: String,
+    total: Duration,
+    books: HashMap<String, Duration>,
+}
+
+/// Why the listening is stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Curfew,
+    Daily,
+    /// Only the stages after the cover of the book
+    Book,
+}
+
+/// Count the listening time and enforce the limits
+pub struct Parental {
+    dir: PathBuf,
+    limits: Limits,
+    usage: Usage,
+    saved: Usage,
+    /// Book being played and since when it is counted
+    playing: Option<(String, Instant)>,
+}
+
+fn json_read<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T> {
+    let file = File::open(path)?;
+    Ok(serde_json::from_reader(BufReader::new(file))?)
+}
+
+/// A limit of the settings, an invalid limit is disabled
+fn limit_get<T: DeserializeOwned>(limits: &Map<String, Value>, name: &str) -> Option<T> {
+    let value = limits.get(name)?.clone();
+    serde_json::from_value(value).unwrap_or_else(|e| {
+        eprintln!("Invalid parental limit {}: {}", name, e);
+        None
+    })
+}
+
+/// Only the invalid limits are disabled, not every limit
+fn limits_read(path: &Path) -> Result<Limits> {
+    let limits: Map<String, Value> = json_read(path)?;
+    Ok(Limits {
+        daily_quota: limit_get(&limits, "dailyQuota"),
+        book_quota: limit_get(&limits, "bookQuota"),
+        curfew: limit_get(&limits, "curfew"),
+        max_volume: limit_get(&limits, "maxVolume"),
+    })
+}
+
+fn today() -> String {
+    Local::now().date_naive().to_string()
+}
+
+impl Parental {
+    /// Load the limits and the counters from the books directory
+    pub fn load(dir: &Path) -> Self {
+        let usage: Usage = json_read(&dir.join(USAGE_FILE)).unwrap_or_default();
+        let mut parental = Self {
+            dir: dir.to_path_buf(),
+            limits: Limits::default(),
+            saved: usage.clone(),
+            usage,
+            playing: None,
+        };
+        parental.reload();
+        parental
+    }
+
+    /// Reload the limits (they can be changed by the admin interface)
+    pub fn reload(&mut self) {
+        let path = self.dir.join(LIMITS_FILE);
+        self.limits = match fs::exists(&path) {
+            Ok(true) => limits_read(&path).unwrap_or_else(|e| {
+                eprintln!("Cannot load the parental limits: {}", e);
+                Limits::default()
+            }),
+            _ => Limits::default(),
+        };
+    }
+
+    /// Limit of the volume level
+    pub fn max_volume(&self) -> Option<usize> {
+        self.limits.max_volume
+    }
+
+    fn update_at(&mut self, playing: Option<&str>, now: Instant, date: &str) {
+        if self.usage.date != date {
+            self.usage = Usage {
+                date: date.to_string(),
+                ..Default::default()
+            };
+        }
+
+        if let Some((book, since)) = self.playing.take() {
+            let elapsed = now.duration_since(since);
+            self.usage.total += elapsed;
+            *self.usage.books.entry(book).or_default() += elapsed;
+        }
+        self.playing = playing.map(|book| (book.to_string(), now));
+    }
+
+    /// Count the listening time since the previous update, `playing` is
+    /// the book being played now (None if nothing is playing).
+    pub fn update(&mut self, playing: Option<&str>) {
+        self.update_at(playing, Instant::now(), &today());
+    }
+
+    fn limit_at(&self, book: &str, square_one: bool, time: NaiveTime) -> Option<Limit> {
+        let exceeded = |quota: Option<u64>, used: Duration| {
+            quota.is_some_and(|quota| used >= Duration::from_secs(quota * 60))
+        };
+
+        if self
+            .limits
+            .curfew
+            .is_some_and(|curfew| curfew.contains(time))
+        {
+            Some(Limit::Curfew)
+        } else if exceeded(self.limits.daily_quota, self.usage.total) {
+            Some(Limit::Daily)
+        } else if !square_one && exceeded(self.limits.book_quota, self.book_usage(book)) {
+            Some(Limit::Book)
+        } else {
+            None
+        }
+    }
+
+    /// Limit reached for a stage of a book
+    pub fn limit(&self, book: &str, square_one: bool) -> Option<Limit> {
+        self.limit_at(book, square_one, Local::now().time())
+    }
+
+    fn book_usage(&self, book: &str) -> Duration {
+        self.usage.books.get(book).copied().unwrap_or_default()
+    }
+
+    fn remaining_at(&self, book: &str, square_one: bool, time: NaiveTime) -> Option<Duration> {
+        let remaining = |quota: Option<u64>, used: Duration| {
+            quota.map(|quota| Duration::from_secs(quota * 60).saturating_sub(used))
+        };
+        let book_quota = self.limits.book_quota.filter(|_| !square_one);
+
+        [
+            self.limits.curfew.map(|curfew| curfew.until_start(time)),
+            remaining(self.limits.daily_quota, self.usage.total),
+            remaining(book_quota, self.book_usage(book)),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Listening time before the next limit of a stage of a book (None
+    /// without limits), like `limit` the book quota is ignored on the cover
+    pub fn remaining(&self, book: &str, square_one: bool) -> Option<Duration> {
+        self.remaining_at(book, square_one, Local::now().time())
+    }
+
+    /// Write the counters like the state (only if something has changed)
+    pub fn save(&mut self) -> Result<()> {
+        if self.usage == self.saved {
+            return Ok(());
+        }
+
+        let path = self.dir.join(USAGE_FILE);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, &self.usage)?;
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        self.saved = self.usage.clone();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn limits() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        fs::write(
+            dir.path().join(LIMITS_FILE),
+            r#"{"dailyQuota": 30, "bookQuota": 20, "curfew": "20:00-07:00", "maxVolume": 6}"#,
+        )
+        .unwrap();
+
+        let mut parental = Parental::load(dir.path());
+        assert_eq!(parental.limits.daily_quota, Some(30));
+        assert_eq!(parental.max_volume(), Some(6));
+        assert_eq!(
+            parental.limit_at("a", false, time("21:00")),
+            Some(Limit::Curfew)
+        );
+        assert_eq!(parental.limit_at("a", false, time("12:00")), None);
+        assert_eq!(
+            parental.remaining_at("a", false, time("12:00")),
+            Some(Duration::from_secs(20 * 60))
+        );
+
+        /* Only the playing time is counted */
+        let start = Instant::now();
+        let minutes = |m: u64| start + Duration::from_secs(m * 60);
+        parental.update_at(Some("a"), start, "2025-01-01");
+        parental.update_at(None, minutes(20), "2025-01-01");
+        parental.update_at(Some("b"), minutes(60), "2025-01-01");
+        assert_eq!(
+            parental.limit_at("a", false, time("12:00")),
+            Some(Limit::Book)
+        );
+        assert_eq!(parental.limit_at("a", true, time("12:00")), None);
+        assert_eq!(
+            parental.remaining_at("a", false, time("12:00")),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            parental.remaining_at("a", true, time("12:00")),
+            Some(Duration::from_secs(10 * 60))
+        );
+        assert_eq!(parental.limit_at("b", false, time("12:00")), None);
+        assert_eq!(
+            parental.remaining_at("b", false, time("12:00")),
+            Some(Duration::from_secs(10 * 60))
+        );
+        parental.update_at(Some("b"), minutes(70), "2025-01-01");
+        assert_eq!(
+            parental.limit_at("b", true, time("12:00")),
+            Some(Limit::Daily)
+        );
+
+        /* The counters are saved and reset every day */
+        parental.save().expect("cannot save");
+        let mut parental = Parental::load(dir.path());
+        assert_eq!(parental.usage.total, Duration::from_secs(30 * 60));
+        parental.update_at(None, minutes(80), "2025-01-02");
+        assert_eq!(parental.limit_at("a", false, time("12:00")), None);
+
+        /* An invalid limit doesn't disable the other ones */
+        fs::write(
+            dir.path().join(LIMITS_FILE),
+            r#"{"dailyQuota": 30, "curfew": "25:00-29:00", "maxVolume": "loud"}"#,
+        )
+        .unwrap();
+        parental.reload();
+        assert_eq!(
+            parental.limits,
+            Limits {
+                daily_quota: Some(30),
+                ..Default::default()
+            }
+        );
+
+        fs::remove_file(dir.path().join(LIMITS_FILE)).unwrap();
+        parental.reload();
+        assert_eq!(parental.limits, Limits::default());
+        assert_eq!(parental.remaining_at("a", false, time("12:00")), None);
+    }
+}
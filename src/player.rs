@@ -15,26 +15,119 @@
  * along with this program.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use anyhow::Result;
-use rodio::{OutputStream, OutputStreamBuilder, Sink, play, source::EmptyCallback};
-use std::io::BufReader;
+use anyhow::{Context, Result};
+use rodio::{Decoder, OutputStream, Sink, Source, source::EmptyCallback};
+use std::{
+    io::BufReader,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 
-use crate::FileReader;
+use crate::{FileReader, Output, Volume};
+
+/// A seek forward stops before the end of the audio
+const SEEK_END: Duration = Duration::from_secs(1);
+
+/// Audio queued after the current one
+struct Queued {
+    /// Identify the audio for `take_queued`
+    key: String,
+    /// Set when the queued audio must not be played
+    cancel: Arc<AtomicBool>,
+    /// Own sink of a crossfade (None if appended to the current sink)
+    sink: Option<Sink>,
+    /// Silence before a crossfade
+    delay: Duration,
+    duration: Option<Duration>,
+}
 
 pub struct Player {
-    stream_handle: OutputStream,
+    output: Output,
+    /// None until the device is available
+    stream_handle: Option<OutputStream>,
+    /// Set by the stream on errors (like a device which is unplugged)
+    lost: Arc<AtomicBool>,
+    on_lost: Arc<dyn Fn() + Send + Sync>,
     sink: Option<Sink>,
-    volume: f32,
+    /// Silence before the current audio (see `Queued`)
+    delay: Duration,
+    /// Start of the current audio when it's resumed, the sink counts from
+    /// zero
+    offset: Duration,
+    /// Total duration of the current audio (if known)
+    duration: Option<Duration>,
+    queued: Option<Queued>,
+    volume: Volume,
+    /// Factor of the loudness normalization
+    gain: f32,
+    /// Factor of the volume while fading out (sleep timer)
+    fade: f32,
+    /// Volume of the sinks (1.0 if the volume is set by the mixer)
+    sink_volume: f32,
 }
 
 impl Player {
-    pub fn new() -> Result<Self> {
-        let stream_handle = OutputStreamBuilder::open_default_stream()?;
-        Ok(Self {
-            stream_handle,
+    /// The device is opened now and when an audio is played if it was not
+    /// available. The callback is called when the device is lost, then
+    /// `recover` must be called.
+    pub fn new<F>(volume: Volume, output: Output, on_lost: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let mut player = Self {
+            output,
+            stream_handle: None,
+            lost: Arc::default(),
+            on_lost: Arc::new(on_lost),
             sink: None,
-            volume: 0.2,
-        })
+            delay: Duration::ZERO,
+            offset: Duration::ZERO,
+            duration: None,
+            queued: None,
+            volume,
+            gain: 1.0,
+            fade: 1.0,
+            sink_volume: 1.0,
+        };
+        if let Err(e) = player.stream_open() {
+            eprintln!("Cannot open the audio device: {}", e);
+        }
+        player.volume_apply();
+        player
+    }
+
+    fn stream_open(&mut self) -> Result<&OutputStream> {
+        if self.stream_handle.is_none() {
+            let (lost, on_lost) = (self.lost.clone(), self.on_lost.clone());
+            let stream = self.output.open(move |e| {
+                eprintln!("Audio device error: {}", e);
+                if !lost.swap(true, Ordering::Relaxed) {
+                    on_lost();
+                }
+            })?;
+            self.stream_handle = Some(stream);
+        }
+        self.stream_handle.as_ref().context("No audio device")
+    }
+
+    /// Open the device again after an error, it returns the position of
+    /// the interrupted audio (None if the device was not lost).
+    pub fn recover(&mut self) -> Option<Duration> {
+        if !self.lost.swap(false, Ordering::Relaxed) {
+            return None;
+        }
+
+        let position = self.position();
+        self.stop();
+        self.sink = None;
+        self.stream_handle = None;
+        if let Err(e) = self.stream_open() {
+            eprintln!("Cannot open the audio device: {}", e);
+        }
+        Some(position)
     }
 
     pub fn play<F>(
@@ -45,29 +138,234 @@ impl Player {
     where
         F: Fn() + Send + 'static,
     {
-        let mixer = self.stream_handle.mixer();
-        let reader = BufReader::new(audio);
-        let sink = play(mixer, reader)?;
+        self.play_from(audio, Duration::ZERO, end_cb)
+    }
 
+    /// Play the audio from an offset (in time)
+    pub fn play_from<F>(
+        &mut self,
+        audio: FileReader,
+        offset: Duration,
+        end_cb: F,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: Fn() + Send + 'static,
+    {
+        let sink = Sink::connect_new(self.stream_open()?.mixer());
+        self.play_on(sink, audio, offset, end_cb)
+    }
+
+    fn play_on<F>(
+        &mut self,
+        sink: Sink,
+        audio: FileReader,
+        offset: Duration,
+        end_cb: F,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: Fn() + Send + 'static,
+    {
+        let mut source = Self::decode(audio)?;
+
+        self.offset = Duration::ZERO;
+        if !offset.is_zero() {
+            match source.try_seek(offset) {
+                Ok(()) => self.offset = offset,
+                Err(e) => eprintln!("Cannot seek to {:?}: {}", offset, e),
+            }
+        }
+
+        self.unqueue();
+        self.duration = source.total_duration();
+        self.delay = Duration::ZERO;
+
+        sink.append(source.amplify(self.gain));
         sink.append(EmptyCallback::new(Box::new(move || {
             println!("End of stream");
             end_cb();
         })));
 
-        sink.set_volume(self.volume);
+        sink.set_volume(self.sink_volume);
         self.sink = Some(sink);
 
         Ok(())
     }
 
-    pub fn stop(&self) {
+    fn decode(mut audio: FileReader) -> Result<Decoder<BufReader<FileReader>>> {
+        let byte_len = audio.size()?;
+        let reader = BufReader::new(audio);
+        Ok(Decoder::builder()
+            .with_data(reader)
+            .with_byte_len(byte_len)
+            .with_seekable(true)
+            .build()?)
+    }
+
+    /// Queue the next audio, it's decoded now and it starts without gap
+    /// at the end of the current audio. With a crossfade, it starts before
+    /// the end with a fade in (only if the duration of the current audio is
+    /// known). The key is given back to `take_queued`.
+    pub fn queue<F>(
+        &mut self,
+        key: String,
+        audio: FileReader,
+        crossfade: Duration,
+        end_cb: F,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: Fn() + Send + 'static,
+    {
+        self.unqueue();
+        let Some(sink) = &self.sink else {
+            return Err("Nothing is playing".into());
+        };
+
+        let source = Self::decode(audio)?.amplify(self.gain);
+        let duration = source.total_duration();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let cancel_source = cancel.clone();
+        let source = source
+            .stoppable()
+            .periodic_access(Duration::from_millis(5), move |source| {
+                if cancel_source.load(Ordering::Relaxed) {
+                    source.stop();
+                }
+            });
+        let cancel_cb = cancel.clone();
+        let end_cb = EmptyCallback::new(Box::new(move || {
+            if !cancel_cb.load(Ordering::Relaxed) {
+                println!("End of stream");
+                end_cb();
+            }
+        }));
+
+        let remaining = self
+            .duration
+            .map(|duration| duration.saturating_sub(self.position()));
+        let (sink, delay) = match remaining {
+            Some(remaining) if !crossfade.is_zero() && remaining > crossfade => {
+                let delay = remaining - crossfade;
+                let mixer = self
+                    .stream_handle
+                    .as_ref()
+                    .ok_or("No audio device")?
+                    .mixer();
+                let next = Sink::connect_new(mixer);
+                next.append(source.fade_in(crossfade).delay(delay));
+                next.append(end_cb);
+                next.set_volume(sink.volume());
+                if sink.is_paused() {
+                    next.pause();
+                }
+                (Some(next), delay)
+            }
+            _ => {
+                sink.append(source);
+                sink.append(end_cb);
+                (None, Duration::ZERO)
+            }
+        };
+
+        self.queued = Some(Queued {
+            key,
+            cancel,
+            sink,
+            delay,
+            duration,
+        });
+        Ok(())
+    }
+
+    /// It returns true if the audio of this key was queued and if the
+    /// current audio has ended (`eos`), then it's the current audio (already
+    /// playing). Otherwise the queued audio is dropped.
+    pub fn take_queued(&mut self, key: &str, eos: bool) -> bool {
+        match self.queued.take() {
+            Some(queued) if eos && queued.key == key => {
+                if let Some(sink) = queued.sink {
+                    self.sink = Some(sink);
+                }
+                self.delay = queued.delay;
+                self.offset = Duration::ZERO;
+                self.duration = queued.duration;
+                true
+            }
+            queued => {
+                self.queued = queued;
+                self.unqueue();
+                false
+            }
+        }
+    }
+
+    /// Drop the queued audio (if any)
+    pub fn unqueue(&mut self) {
+        if let Some(queued) = self.queued.take() {
+            queued.cancel.store(true, Ordering::Relaxed);
+            if let Some(sink) = queued.sink {
+                sink.stop();
+            }
+        }
+    }
+
+    /// Elapsed time of the current audio
+    pub fn position(&self) -> Duration {
+        match &self.sink {
+            Some(sink) if !sink.empty() => sink.get_pos().saturating_sub(self.delay) + self.offset,
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Progress of the current audio (0.0 to 1.0), None if the duration is
+    /// unknown
+    pub fn progress(&self) -> Option<f32> {
+        let duration = self.duration.filter(|duration| !duration.is_zero())?;
+        Some((self.position().as_secs_f32() / duration.as_secs_f32()).min(1.0))
+    }
+
+    /// Seek forward (or backward with negative seconds) in the current
+    /// audio, it stops just before the end. It returns the new position.
+    pub fn seek_relative(&mut self, seconds: i64) -> Result<Duration> {
+        let position = self.position();
+        let step = Duration::from_secs(seconds.unsigned_abs());
+        let mut target = match seconds < 0 {
+            true => position.saturating_sub(step),
+            false => position + step,
+        };
+        if let Some(duration) = self.duration {
+            target = target.min(duration.saturating_sub(SEEK_END));
+        }
+
+        let sink = self
+            .sink
+            .as_ref()
+            .filter(|sink| !sink.empty())
+            .context("Nothing is playing")?;
+        sink.try_seek(target + self.delay)
+            .map_err(|e| anyhow::anyhow!("Cannot seek to {:?}: {}", target, e))?;
+        self.offset = Duration::ZERO; // The decoder seeks from the start
+
+        /* The delay of a queued crossfade is now wrong */
+        if self
+            .queued
+            .as_ref()
+            .is_some_and(|queued| queued.sink.is_some())
+        {
+            self.unqueue();
+        }
+        Ok(target)
+    }
+
+    pub fn stop(&mut self) {
+        self.unqueue();
         if let Some(sink) = &self.sink {
             sink.stop();
         }
     }
 
     pub fn toggle_pause(&self) {
-        if let Some(sink) = &self.sink {
+        let queued = self.queued.as_ref().and_then(|queued| queued.sink.as_ref());
+        for sink in self.sink.iter().chain(queued) {
             if sink.is_paused() {
                 sink.play();
             } else {
@@ -76,39 +374,216 @@ impl Player {
         }
     }
 
-    pub fn is_paused(&self) -> bool {
+    /// True if an audio is playing (not paused)
+    pub fn is_playing(&self) -> bool {
         match &self.sink {
-            Some(sink) => sink.is_paused(),
+            Some(sink) => !sink.empty() && !sink.is_paused(),
             None => false,
         }
     }
 
-    pub fn get_volume(&self) -> usize {
+    pub fn is_paused(&self) -> bool {
         match &self.sink {
-            Some(sink) => (sink.volume() * 10.0).round() as usize,
-            None => 0,
+            Some(sink) => sink.is_paused(),
+            None => false,
         }
     }
 
-    pub fn volume_up(&mut self) {
-        if let Some(sink) = &self.sink {
-            let mut volume = sink.volume();
-            if volume < 1.0 {
-                volume = volume + 0.1;
+    /// Apply the volume on the current audio (if any)
+    /// The mixer is used if available, otherwise the sinks are scaled
+    fn volume_apply(&mut self) {
+        let gain = self.volume.gain() * self.fade;
+        self.sink_volume = match self.output.mixer_set(gain) {
+            Ok(true) => 1.0,
+            Ok(false) => gain,
+            Err(e) => {
+                eprintln!("Cannot set the mixer: {}", e);
+                gain
             }
-            self.volume = volume;
-            sink.set_volume(volume);
+        };
+
+        let queued = self.queued.as_ref().and_then(|queued| queued.sink.as_ref());
+        for sink in self.sink.iter().chain(queued) {
+            sink.set_volume(self.sink_volume);
         }
     }
 
+    /// Volume level (1 to 10), even when nothing is playing
+    pub fn get_volume(&self) -> usize {
+        self.volume.level()
+    }
+
+    pub fn set_volume(&mut self, level: usize) {
+        self.volume.set_level(level);
+        self.volume_apply();
+    }
+
+    pub fn volume_up(&mut self) {
+        self.volume.up();
+        self.volume_apply();
+    }
+
     pub fn volume_down(&mut self) {
-        if let Some(sink) = &self.sink {
-            let mut volume = sink.volume();
-            if volume > 0.2 {
-                volume = volume - 0.1;
+        self.volume.down();
+        self.volume_apply();
+    }
+
+    /// Limit the volume in night mode (1 to 10, None without limit)
+    pub fn set_volume_max(&mut self, max: Option<usize>) {
+        self.volume.set_night_max(max);
+        self.volume_apply();
+    }
+
+    /// Limit the volume with the parental settings (None without limit)
+    pub fn set_volume_parental(&mut self, max: Option<usize>) {
+        self.volume.set_parental_max(max);
+        self.volume_apply();
+    }
+
+    /// Gain of the next played or queued audio for the loudness
+    /// normalization (1.0 keeps the audio as it is)
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+    }
+
+    /// Fade out the volume (1.0 is the normal volume), the volume steps are
+    /// unchanged.
+    pub fn set_fade(&mut self, fade: f32) {
+        self.fade = fade.clamp(0.0, 1.0);
+        self.volume_apply();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::File, io::Write, path::Path, sync::atomic::AtomicUsize, thread};
+
+    /// Silence of 16-bit mono PCM in a WAV file
+    fn wav(path: &Path, seconds: u32) -> FileReader {
+        let rate = 8000u32;
+        let len = rate * seconds * 2;
+        let mut file = File::create(path).expect("cannot create wav");
+        file.write_all(b"RIFF").unwrap();
+        file.write_all(&(36 + len).to_le_bytes()).unwrap();
+        file.write_all(b"WAVEfmt ").unwrap();
+        file.write_all(&16u32.to_le_bytes()).unwrap();
+        file.write_all(&1u16.to_le_bytes()).unwrap(); // PCM
+        file.write_all(&1u16.to_le_bytes()).unwrap(); // mono
+        file.write_all(&rate.to_le_bytes()).unwrap();
+        file.write_all(&(rate * 2).to_le_bytes()).unwrap();
+        file.write_all(&2u16.to_le_bytes()).unwrap();
+        file.write_all(&16u16.to_le_bytes()).unwrap();
+        file.write_all(b"data").unwrap();
+        file.write_all(&len.to_le_bytes()).unwrap();
+        file.write_all(&vec![0; len as usize]).unwrap();
+        FileReader::Plain(File::open(path).unwrap())
+    }
+
+    fn assert_near(position: Duration, expected: Duration) {
+        let diff = position.abs_diff(expected);
+        assert!(
+            diff < Duration::from_millis(20),
+            "{position:?} != {expected:?}"
+        );
+    }
+
+    #[test]
+    fn resume() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let mut player = Player::new(Volume::new(5, 1, 10, 1), Output::default(), || ());
+
+        /* The position counts from the start of the audio */
+        let (sink, mut output) = Sink::new();
+        let audio = wav(&dir.path().join("a.wav"), 10);
+        player
+            .play_on(sink, audio, Duration::from_secs(4), || ())
+            .expect("cannot play");
+        assert_near(player.position(), Duration::from_secs(4));
+
+        output.by_ref().take(4000).for_each(drop); // 500 ms
+        assert_near(player.position(), Duration::from_millis(4500));
+        let progress = player.progress().expect("no progress");
+        assert!((progress - 0.45).abs() < 0.01, "{progress}");
+    }
+
+    #[test]
+    fn seek() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let mut player = Player::new(Volume::new(5, 1, 10, 1), Output::default(), || ());
+
+        let (sink, mut output) = Sink::new();
+        let audio = wav(&dir.path().join("a.wav"), 20);
+        player
+            .play_on(sink, audio, Duration::from_secs(4), || ())
+            .expect("cannot play");
+
+        /* The seeks are done while the audio is played (about 1 ms every
+         * 1 ms) */
+        let stop = Arc::new(AtomicBool::new(false));
+        let playing = stop.clone();
+        let output = thread::spawn(move || {
+            while !playing.load(Ordering::Relaxed) {
+                output.by_ref().take(8).for_each(drop);
+                thread::sleep(Duration::from_millis(1));
             }
-            self.volume = volume;
-            sink.set_volume(volume);
-        }
+        });
+
+        let position = player.seek_relative(3).expect("cannot seek");
+        assert_near(position, Duration::from_secs(7));
+        assert!(player.position() >= position);
+        assert_near(player.position(), position);
+
+        let position = player.seek_relative(-10).expect("cannot seek");
+        assert_eq!(position, Duration::ZERO);
+        assert_near(player.position(), position);
+
+        /* It stops before the end */
+        let position = player.seek_relative(60).expect("cannot seek");
+        assert_eq!(position, Duration::from_secs(19));
+        assert_near(player.position(), position);
+
+        stop.store(true, Ordering::Relaxed);
+        output.join().unwrap();
+    }
+
+    #[test]
+    fn queue() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let mut player = Player::new(Volume::new(5, 1, 10, 1), Output::default(), || ());
+        let ended = Arc::new(AtomicUsize::new(0));
+
+        /* The queued audio is taken at the end of the current audio */
+        let (sink, _output) = Sink::new();
+        let audio = wav(&dir.path().join("a.wav"), 1);
+        player
+            .play_on(sink, audio, Duration::ZERO, || ())
+            .expect("cannot play");
+        let audio = wav(&dir.path().join("b.wav"), 2);
+        player
+            .queue("b".into(), audio, Duration::ZERO, || ())
+            .expect("cannot queue");
+        assert!(player.take_queued("b", true));
+        assert_eq!(player.duration, Some(Duration::from_secs(2)));
+
+        /* A manual OK drops it, it's neither played nor ended */
+        let (sink, mut output) = Sink::new();
+        let audio = wav(&dir.path().join("a.wav"), 1);
+        player
+            .play_on(sink, audio, Duration::ZERO, || ())
+            .expect("cannot play");
+        let audio = wav(&dir.path().join("b.wav"), 2);
+        let ended_cb = ended.clone();
+        player
+            .queue("b".into(), audio, Duration::ZERO, move || {
+                ended_cb.fetch_add(1, Ordering::Relaxed);
+            })
+            .expect("cannot queue");
+        assert!(!player.take_queued("b", false));
+        assert!(player.queued.is_none());
+        assert_eq!(player.duration, Some(Duration::from_secs(1)));
+
+        output.by_ref().take(32000).for_each(drop); // 4 s
+        assert_eq!(ended.load(Ordering::Relaxed), 0);
     }
 }
@@ -0,0 +1,212 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::{Result, bail};
+use std::{fs, path::Path, str::FromStr};
+
+use crate::book::{Book, BookInfo};
+
+const PLAYLIST_EXTENSION: &str = "playlist";
+
+/// Story of a book in a playlist
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    /// Options chosen in the menus from the cover (starting at 0)
+    Path(Vec<usize>),
+    /// Uuid of a stage (or the beginning of an uuid)
+    Stage(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Item {
+    /// Book identifier
+    pub book: String,
+    pub target: Target,
+}
+
+impl Item {
+    /// Move the book to the story of the item
+    pub fn select(&self, book: &mut Book) -> Option<()> {
+        match &self.target {
+            Target::Path(path) => book.story_path(path),
+            Target::Stage(uuid) => book.stage_select(uuid),
+        }
+    }
+}
+
+/// An item is a book identifier followed by the options chosen in the
+/// menus (starting at 1, like 2/1) or by a stage uuid. The first story is
+/// used when only the book is given.
+impl FromStr for Item {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+        let book = fields.next().ok_or("Missing book")?.to_string();
+
+        let target = match fields.next() {
+            None => Target::Path(Vec::new()),
+            Some(path)
+                if path
+                    .split('/')
+                    .all(|option| option.parse::<usize>().is_ok()) =>
+            {
+                let path = path
+                    .split('/')
+                    .map(|option| option.parse::<usize>().unwrap_or_default())
+                    .map(|option| option.checked_sub(1).ok_or("The options start at 1"))
+                    .collect::<Result<_, _>>()?;
+                Target::Path(path)
+            }
+            Some(uuid) => Target::Stage(uuid.to_string()),
+        };
+        if fields.next().is_some() {
+            return Err(format!("{s}: expected a book and a path or a stage"));
+        }
+
+        Ok(Self { book, target })
+    }
+}
+
+/// Stories of several books played back-to-back. A playlist is a
+/// `.playlist` file of the books directory with one item per line (the
+/// lines starting with # are comments).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Playlist {
+    items: Vec<Item>,
+    /// Item being played (None on the cover)
+    current: Option<usize>,
+}
+
+impl Playlist {
+    pub fn is_playlist(path: &Path) -> bool {
+        path.is_file()
+            && path
+                .extension()
+                .is_some_and(|ext| ext == PLAYLIST_EXTENSION)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let items = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Item::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(anyhow::Error::msg)?;
+        if items.is_empty() {
+            bail!("Empty playlist");
+        }
+
+        Ok(Self {
+            items,
+            current: None,
+        })
+    }
+
+    /// Metadata of the playlist as a book
+    pub fn info(&self, id: &str) -> BookInfo {
+        let title = Path::new(id).file_stem().unwrap_or_default();
+        BookInfo {
+            id: id.to_string(),
+            uuid: None,
+            title: Some(title.to_string_lossy().to_string()),
+            description: None,
+            thumbnail: None,
+            format: PLAYLIST_EXTENSION.to_string(),
+            version: 1,
+            night_mode: false,
+            stage_nodes: self.items.len(),
+            action_nodes: 0,
+            duration: None,
+        }
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    /// Item being played, None on the cover
+    pub fn current(&self) -> Option<&Item> {
+        self.items.get(self.current?)
+    }
+
+    /// Go to the next item (the first one from the cover), None after the
+    /// last one
+    pub fn advance(&mut self) -> Option<&Item> {
+        let next = self.current.map_or(0, |current| current + 1);
+        if next >= self.items.len() {
+            return None;
+        }
+        self.current = Some(next);
+        self.items.get(next)
+    }
+
+    /// Back to the cover
+    pub fn reset(&mut self) {
+        self.current = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let path = dir.path().join("bedtime.playlist");
+        fs::write(
+            &path,
+            "# Three stories then sleep\nbook-a 2/1\n\nbook-b 5ff37664\nbook-c\n",
+        )
+        .unwrap();
+        assert!(Playlist::is_playlist(&path));
+
+        let mut playlist = Playlist::load(&path).expect("cannot load playlist");
+        assert_eq!(
+            playlist
+                .items()
+                .iter()
+                .map(|item| &item.target)
+                .collect::<Vec<_>>(),
+            [
+                &Target::Path(vec![1, 0]),
+                &Target::Stage(String::from("5ff37664")),
+                &Target::Path(Vec::new()),
+            ]
+        );
+        assert_eq!(
+            playlist.info("bedtime.playlist").title.as_deref(),
+            Some("bedtime")
+        );
+
+        assert!(playlist.current().is_none());
+        for book in ["book-a", "book-b", "book-c"] {
+            assert_eq!(playlist.advance().expect("item not found").book, book);
+            assert_eq!(playlist.current().expect("item not found").book, book);
+        }
+        assert!(playlist.advance().is_none());
+        playlist.reset();
+        assert!(playlist.current().is_none());
+
+        assert!("book 0/1".parse::<Item>().is_err());
+        assert!("book 1 2".parse::<Item>().is_err());
+        fs::write(&path, "# Nothing\n").unwrap();
+        assert!(Playlist::load(&path).is_err());
+    }
+}
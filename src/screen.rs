@@ -29,6 +29,8 @@ use crate::decrypt::FileReader;
 pub struct Screen {
     fb: Framebuffer,
     name: String,
+    /// Brightness before dimming, restored by `on`
+    brightness: Option<String>,
 }
 
 impl Screen {
@@ -48,7 +50,11 @@ impl Screen {
          */
         fs::write(format!("/sys/class/graphics/{}/blank", dev), "0")?;
 
-        Ok(Self { fb, name })
+        Ok(Self {
+            fb,
+            name,
+            brightness: None,
+        })
     }
 
     pub fn off(&self) -> io::Result<()> {
@@ -56,11 +62,29 @@ impl Screen {
         fs::write(bl_power, "4")
     }
 
-    pub fn on(&self) -> io::Result<()> {
+    pub fn on(&mut self) -> io::Result<()> {
+        if let Some(brightness) = self.brightness.take() {
+            let path = format!("/sys/class/backlight/{}/brightness", self.name);
+            fs::write(path, brightness)?;
+        }
         let bl_power = format!("/sys/class/backlight/{}/bl_power", self.name);
         fs::write(bl_power, "0")
     }
 
+    /// Lower the backlight to 10% (restored by `on`)
+    pub fn dim(&mut self) -> io::Result<()> {
+        let path = format!("/sys/class/backlight/{}/brightness", self.name);
+        if self.brightness.is_none() {
+            self.brightness = Some(fs::read_to_string(&path)?.trim().to_string());
+        }
+        let max_brightness = format!("/sys/class/backlight/{}/max_brightness", self.name);
+        let max: u32 = fs::read_to_string(max_brightness)?
+            .trim()
+            .parse()
+            .map_err(io::Error::other)?;
+        fs::write(path, (max / 10).max(1).to_string())
+    }
+
     pub fn draw(
         &mut self,
         image: &mut FileReader,
@@ -100,6 +124,35 @@ impl Screen {
         Ok(())
     }
 
+    /// Draw a progress bar at the bottom of the current image
+    pub fn progress(&mut self, ratio: f32) -> Result<(), Box<dyn std::error::Error>> {
+        let width = self.fb.var_screen_info.xres;
+        let height = self.fb.var_screen_info.yres;
+        let line_length = self.fb.fix_screen_info.line_length;
+
+        /* Bar with a black border, white for the elapsed time */
+        let margin = (width / 12).max(2);
+        let (left, right) = (margin, width - margin);
+        let (top, bottom) = (height - margin - 10, height - margin);
+        let done = left + ((right - left) as f32 * ratio.clamp(0.0, 1.0)) as u32;
+
+        for y in top - 2..bottom + 2 {
+            for x in left - 2..right + 2 {
+                let offset = ((y * line_length / 2) + x) as usize;
+                let inside = y >= top && y < bottom && x >= left && x < right;
+                let rgb565: u16 = match inside {
+                    false => 0x0000,
+                    true if x < done => 0xFFFF,
+                    true => 0x4208, // dark gray
+                };
+                self.fb.frame[offset * 2] = (rgb565 & 0xFF) as u8;
+                self.fb.frame[offset * 2 + 1] = (rgb565 >> 8) as u8;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn clear(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         self.fb.frame.fill(0);
         Ok(())
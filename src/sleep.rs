@@ -0,0 +1,169 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::timeout::Timeout;
+
+/// Durations of the timer in minutes (0 is off), cycled with a gesture
+const SLEEP_MINUTES: [u64; 4] = [0, 15, 30, 45];
+/// The volume is faded out in the final seconds
+const FADE_STEPS: u32 = 10;
+const FADE_STEP: Duration = Duration::from_secs(1);
+
+/// Sleep timer, the callback is called at the beginning of the fade and
+/// then at every step of the fade.
+pub struct Sleep {
+    index: usize,
+    callback: Arc<dyn Fn() + Send + Sync>,
+    timeout: Option<Timeout>,
+    /// When the next step is due, a cleared timeout can already have called
+    /// the callback then its step is ignored.
+    deadline: Option<Instant>,
+    fade: u32,
+}
+
+impl Sleep {
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Self {
+            index: 0,
+            callback: Arc::new(callback),
+            timeout: None,
+            deadline: None,
+            fade: 0,
+        }
+    }
+
+    /// Duration of the timer in minutes (0 if off)
+    pub fn minutes(&self) -> u64 {
+        SLEEP_MINUTES[self.index]
+    }
+
+    fn schedule(&mut self, delay: Duration) {
+        if let Some(ref mut timeout) = self.timeout {
+            timeout.clear();
+        }
+        let callback = self.callback.clone();
+        self.deadline = Some(Instant::now() + delay);
+        self.timeout = Some(Timeout::set(delay, move || callback()));
+    }
+
+    /// Select the next duration (off after the longest one), the timer
+    /// restarts from now.
+    pub fn cycle(&mut self) -> u64 {
+        self.index = (self.index + 1) % SLEEP_MINUTES.len();
+        self.fade = 0;
+
+        match self.minutes() {
+            0 => {
+                if let Some(ref mut timeout) = self.timeout {
+                    timeout.clear();
+                }
+                self.timeout = None;
+                self.deadline = None;
+            }
+            minutes => {
+                let delay = Duration::from_secs(minutes * 60);
+                self.schedule(delay.saturating_sub(FADE_STEP * FADE_STEPS));
+            }
+        }
+
+        self.minutes()
+    }
+
+    /// Next step of the fade (to call from the callback), it returns the
+    /// factor of the volume or None when it's time to power off.
+    pub fn tick(&mut self) -> Option<f32> {
+        self.tick_at(Instant::now())
+    }
+
+    fn tick_at(&mut self, now: Instant) -> Option<f32> {
+        if self.minutes() == 0 {
+            return Some(1.0);
+        }
+        /* Sent before the timer was restarted */
+        if self.deadline.is_some_and(|deadline| now < deadline) {
+            return Some(self.factor());
+        }
+        if self.fade >= FADE_STEPS {
+            return None;
+        }
+
+        self.fade += 1;
+        self.schedule(FADE_STEP);
+        Some(self.factor())
+    }
+
+    fn factor(&self) -> f32 {
+        (FADE_STEPS - self.fade) as f32 / FADE_STEPS as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Step when the timer is due
+    fn tick_due(sleep: &mut Sleep) -> Option<f32> {
+        let deadline = sleep.deadline.expect("no timer");
+        sleep.tick_at(deadline)
+    }
+
+    #[test]
+    fn sleep() {
+        let mut sleep = Sleep::new(|| {});
+        assert_eq!(sleep.minutes(), 0);
+        assert_eq!(sleep.tick(), Some(1.0));
+
+        assert_eq!(sleep.cycle(), 15);
+        let factors: Vec<f32> = std::iter::from_fn(|| tick_due(&mut sleep)).collect();
+        assert_eq!(factors.len(), FADE_STEPS as usize);
+        assert!(factors.windows(2).all(|pair| pair[0] > pair[1]));
+        assert_eq!(factors.last(), Some(&0.0));
+
+        /* A new duration restarts the timer */
+        assert_eq!(sleep.cycle(), 30);
+        assert_eq!(tick_due(&mut sleep), Some(0.9));
+        assert_eq!(sleep.cycle(), 45);
+        assert_eq!(sleep.cycle(), 0);
+        assert_eq!(sleep.tick(), Some(1.0));
+    }
+
+    #[test]
+    fn restart_while_fading() {
+        let mut sleep = Sleep::new(|| {});
+        assert_eq!(sleep.cycle(), 15);
+        assert_eq!(tick_due(&mut sleep), Some(0.9));
+        let stale = sleep.deadline.expect("no timer");
+
+        /* The step sent before the restart doesn't fade */
+        assert_eq!(sleep.cycle(), 30);
+        let deadline = sleep.deadline.expect("no timer");
+        assert!(deadline > stale + Duration::from_secs(29 * 60));
+        for _ in 0..=FADE_STEPS {
+            assert_eq!(sleep.tick_at(stale), Some(1.0));
+        }
+        assert_eq!(sleep.deadline, Some(deadline));
+        assert_eq!(sleep.tick_at(deadline), Some(0.9));
+    }
+}
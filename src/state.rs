@@ -0,0 +1,122 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, Write},
+    path::Path,
+    time::Duration,
+};
+
+/// Where the reader is in a book
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Position {
+    pub stage_node: Option<String>,
+    pub action_node: Option<String>,
+    pub action_index: usize,
+    #[serde(default)]
+    pub audio_offset: Duration,
+}
+
+/// Snapshot of the library, restored after a reload or a power cycle
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct State {
+    pub book: Option<String>,
+    pub positions: HashMap<String, Position>,
+    /// Books already drawn by the shuffle (no repeat until all are drawn)
+    #[serde(default)]
+    pub played: Vec<String>,
+    /// Last volume level
+    #[serde(default)]
+    pub volume: Option<usize>,
+}
+
+/// Which positions are restored when the books are loaded
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Resume {
+    /// Always start on the cover
+    None,
+    /// Resume only the story nodes (menus are restarted from the cover)
+    Stories,
+    /// Resume every node
+    #[default]
+    All,
+}
+
+impl State {
+    pub fn load(path: &Path) -> Result<Self> {
+        if !fs::exists(path)? {
+            return Ok(Self::default());
+        }
+
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+        let state = serde_json::from_reader(reader)?;
+        Ok(state)
+    }
+
+    /// Write the state in a temporary file and rename it, then a power
+    /// loss never leaves a truncated file.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_load() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        let path = dir.path().join("state.json");
+
+        let state = State::load(&path).expect("cannot load missing state");
+        assert_eq!(state, State::default());
+
+        let mut state = State {
+            book: Some(String::from("book")),
+            ..Default::default()
+        };
+        state.positions.insert(
+            String::from("book"),
+            Position {
+                stage_node: Some(String::from("stage")),
+                action_node: Some(String::from("action")),
+                action_index: 2,
+                audio_offset: Duration::from_secs(42),
+            },
+        );
+        state.save(&path).expect("cannot save state");
+        assert!(!fs::exists(path.with_extension("tmp")).unwrap());
+
+        let loaded = State::load(&path).expect("cannot load state");
+        assert_eq!(loaded, state);
+    }
+}
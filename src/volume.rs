@@ -0,0 +1,135 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+/// Number of levels (like the volume01.png to volume10.png images)
+pub const VOLUME_LEVELS: usize = 10;
+/// The lowest level is this much quieter than the highest one
+const VOLUME_RANGE_DB: f32 = 30.0;
+
+/// Volume level with a perceptual curve, the level is kept between the
+/// min and the max (the night mode and the parental settings can lower
+/// the max).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Volume {
+    level: usize,
+    min: usize,
+    max: usize,
+    step: usize,
+    night_max: usize,
+    parental_max: usize,
+}
+
+impl Volume {
+    pub fn new(level: usize, min: usize, max: usize, step: usize) -> Self {
+        let min = min.clamp(1, VOLUME_LEVELS);
+        let mut volume = Self {
+            level,
+            min,
+            max: max.clamp(min, VOLUME_LEVELS),
+            step: step.max(1),
+            night_max: VOLUME_LEVELS,
+            parental_max: VOLUME_LEVELS,
+        };
+        volume.set_level(level);
+        volume
+    }
+
+    /// Effective maximum with the limits
+    pub fn max(&self) -> usize {
+        self.max.min(self.night_max).min(self.parental_max)
+    }
+
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    pub fn set_level(&mut self, level: usize) {
+        /* The limits can be lower than the min */
+        self.level = level.max(self.min).min(self.max());
+    }
+
+    pub fn up(&mut self) {
+        self.set_level(self.level + self.step);
+    }
+
+    pub fn down(&mut self) {
+        self.set_level(self.level.saturating_sub(self.step));
+    }
+
+    /// Limit of the night mode (None without limit)
+    pub fn set_night_max(&mut self, max: Option<usize>) {
+        self.night_max = max.unwrap_or(VOLUME_LEVELS).max(1);
+        self.set_level(self.level);
+    }
+
+    /// Limit of the parental settings (None without limit)
+    pub fn set_parental_max(&mut self, max: Option<usize>) {
+        self.parental_max = max.unwrap_or(VOLUME_LEVELS).max(1);
+        self.set_level(self.level);
+    }
+
+    /// Factor for the audio sink, every level is the same loudness step
+    pub fn gain(&self) -> f32 {
+        let levels = (VOLUME_LEVELS - 1) as f32;
+        let db = (self.level as f32 - VOLUME_LEVELS as f32) * VOLUME_RANGE_DB / levels;
+        10f32.powf(db / 20.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume() {
+        let mut volume = Volume::new(5, 2, 8, 1);
+        assert_eq!(volume.level(), 5);
+        volume.set_level(10);
+        assert_eq!(volume.level(), 8);
+        volume.set_level(0);
+        assert_eq!(volume.level(), 2);
+        volume.down();
+        assert_eq!(volume.level(), 2);
+        volume.up();
+        assert_eq!(volume.level(), 3);
+
+        /* Perceptual curve */
+        assert_eq!(Volume::new(VOLUME_LEVELS, 1, 10, 1).gain(), 1.0);
+        let gains: Vec<f32> = (2..=8)
+            .map(|level| {
+                volume.set_level(level);
+                volume.gain()
+            })
+            .collect();
+        let ratios: Vec<f32> = gains.windows(2).map(|pair| pair[1] / pair[0]).collect();
+        assert!(ratios.iter().all(|ratio| (ratio - ratios[0]).abs() < 1e-3));
+
+        /* The lowest limit wins */
+        volume.set_night_max(Some(3));
+        assert_eq!(volume.level(), 3);
+        volume.set_parental_max(Some(1));
+        assert_eq!((volume.level(), volume.max()), (1, 1));
+        volume.set_night_max(None);
+        volume.set_parental_max(None);
+        assert_eq!(volume.level(), 2);
+
+        let mut volume = Volume::new(4, 1, 10, 3);
+        volume.up();
+        volume.up();
+        assert_eq!(volume.level(), 10);
+    }
+}
@@ -0,0 +1,173 @@
+/* Contelia
+ * Copyright (C) 2025  Mathieu Schroeter <mathieu@schroetersa.ch>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use anyhow::Result;
+use nix::errno::Errno;
+use nix::sys::epoll;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Quiet time before to report the changes (an upload writes many files)
+const DEBOUNCE_MS: u16 = 500;
+
+/// Watch the books directory and the books folders (one level)
+pub struct Watcher {
+    dir: PathBuf,
+    inotify: Inotify,
+    epoll: epoll::Epoll,
+    root: WatchDescriptor,
+    books: HashMap<WatchDescriptor, String>,
+}
+
+fn flags() -> AddWatchFlags {
+    AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_MOVED_TO
+}
+
+impl Watcher {
+    pub fn new(dir: &Path) -> Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)?;
+        let root = inotify.add_watch(dir, flags())?;
+
+        let epoll = epoll::Epoll::new(epoll::EpollCreateFlags::EPOLL_CLOEXEC)?;
+        let event = epoll::EpollEvent::new(epoll::EpollFlags::EPOLLIN, 0);
+        epoll.add(&inotify, event)?;
+
+        let mut watcher = Self {
+            dir: dir.to_path_buf(),
+            inotify,
+            epoll,
+            root,
+            books: HashMap::new(),
+        };
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                watcher.watch_book(&path.file_name().unwrap_or_default().to_string_lossy());
+            }
+        }
+
+        Ok(watcher)
+    }
+
+    fn watch_book(&mut self, name: &str) {
+        match self.inotify.add_watch(&self.dir.join(name), flags()) {
+            Ok(wd) => {
+                self.books.insert(wd, name.to_string());
+            }
+            Err(e) => eprintln!("Cannot watch the book {}: {}", name, e),
+        }
+    }
+
+    /// Wait on changes, it returns the names of the changed entries in the
+    /// books directory. The hidden and temporary files (like the state)
+    /// are ignored.
+    pub fn listen(&mut self) -> Result<HashSet<String>> {
+        let mut events = [epoll::EpollEvent::empty(); 1];
+        let mut changed = HashSet::new();
+
+        loop {
+            let timeout = if changed.is_empty() {
+                epoll::EpollTimeout::NONE
+            } else {
+                epoll::EpollTimeout::from(DEBOUNCE_MS)
+            };
+            if self.epoll.wait(&mut events, timeout)? == 0 {
+                return Ok(changed);
+            }
+
+            let inotify_events = match self.inotify.read_events() {
+                Ok(inotify_events) => inotify_events,
+                Err(Errno::EAGAIN) => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            for event in inotify_events {
+                if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                    self.books.remove(&event.wd);
+                    continue;
+                }
+
+                if event.wd != self.root {
+                    if let Some(name) = self.books.get(&event.wd) {
+                        changed.insert(name.clone());
+                    }
+                    continue;
+                }
+
+                let Some(name) = event.name.map(|name| name.to_string_lossy().to_string()) else {
+                    continue;
+                };
+                if name.starts_with('.') || name.ends_with(".tmp") {
+                    continue;
+                }
+
+                if event.mask.contains(AddWatchFlags::IN_ISDIR)
+                    && event
+                        .mask
+                        .intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO)
+                {
+                    self.watch_book(&name);
+                }
+                changed.insert(name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen() {
+        let dir = tempfile::tempdir().expect("cannot create temp dir");
+        fs::create_dir(dir.path().join("old")).unwrap();
+        let mut watcher = Watcher::new(dir.path()).expect("cannot watch");
+
+        fs::create_dir(dir.path().join("book")).unwrap();
+        fs::write(dir.path().join(".contelia-state.json"), "{}").unwrap();
+        fs::write(dir.path().join("book.zip"), "").unwrap();
+        let changed = watcher.listen().expect("cannot listen");
+        assert_eq!(changed, HashSet::from(["book".into(), "book.zip".into()]));
+
+        /* The new folders are watched too */
+        fs::write(dir.path().join("book").join(".factory_disabled"), "").unwrap();
+        fs::write(dir.path().join("old").join("story.json"), "{}").unwrap();
+        let changed = watcher.listen().expect("cannot listen");
+        assert_eq!(changed, HashSet::from(["book".into(), "old".into()]));
+
+        fs::rename(
+            dir.path().join("book.zip"),
+            dir.path().join("book.zip.disabled"),
+        )
+        .unwrap();
+        let changed = watcher.listen().expect("cannot listen");
+        assert_eq!(
+            changed,
+            HashSet::from(["book.zip".into(), "book.zip.disabled".into()])
+        );
+    }
+}